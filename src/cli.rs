@@ -3,7 +3,58 @@ use std::path::PathBuf;
 use clap::Parser;
 
 #[derive(Debug, Parser)]
+#[expect(clippy::struct_excessive_bools)]
 #[structopt(version=env!("CARGO_PKG_VERSION"), about="Open or preview files.")]
 pub(crate) struct CommandLineOpts {
-    pub path: Option<PathBuf>,
+    /// Input path, if not set read data from stdin. Several paths are only supported in identify mode
+    pub paths: Vec<PathBuf>,
+
+    /// Config file to use, instead of the one in the XDG config directory
+    #[arg(long, env = "RSOP_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// In identify mode, output MIME type and matching handlers as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// In identify mode, output the name of the matching filetype section of the config
+    #[arg(long, conflicts_with = "json")]
+    pub filetype: bool,
+
+    /// In identify mode, read NUL separated paths from stdin
+    #[arg(long, conflicts_with = "paths")]
+    pub stdin0: bool,
+
+    /// In identify mode, read newline separated paths from stdin
+    #[arg(long, conflicts_with_all = ["paths", "stdin0"])]
+    pub stdin_lines: bool,
+
+    /// Keep running and serve preview and identify requests from other rsop invocations, to avoid parsing config
+    /// each time (Linux only)
+    #[arg(long, conflicts_with_all = ["paths", "stdin0", "stdin_lines"])]
+    pub daemon: bool,
+
+    /// Log more messages, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log fewer messages, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub quiet: u8,
+
+    /// Write log messages to this file, instead of the destination set in config
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Write a config file template, to the path set by --config or in the XDG config directory, and exit
+    #[arg(long, conflicts_with_all = ["paths", "stdin0", "stdin_lines", "daemon"])]
+    pub init_config: bool,
+
+    /// With --init-config, write the advanced example config instead of the minimal one
+    #[arg(long, requires = "init_config")]
+    pub advanced: bool,
+
+    /// With --init-config, overwrite config file if it already exists
+    #[arg(long, requires = "init_config")]
+    pub force: bool,
 }
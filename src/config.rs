@@ -1,11 +1,15 @@
 use std::{
     collections::HashMap,
-    fs::File,
-    io::Write as _,
+    env, fmt, fs,
+    ops::Range,
     path::{Path, PathBuf},
+    str::FromStr as _,
 };
 
-#[derive(Debug, serde::Deserialize)]
+use anyhow::Context as _;
+use toml::Spanned;
+
+#[derive(Clone, Debug, serde::Deserialize)]
 pub(crate) struct Filetype {
     #[serde(default)]
     pub extensions: Vec<String>,
@@ -15,6 +19,7 @@ pub(crate) struct Filetype {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize)]
+#[expect(clippy::struct_excessive_bools)]
 pub(crate) struct FileHandler {
     pub command: String,
     #[serde(default = "default_file_handler_wait")]
@@ -24,6 +29,24 @@ pub(crate) struct FileHandler {
     #[serde(default)]
     pub no_pipe: bool,
     pub stdin_arg: Option<String>,
+    pub requires: Option<Requirement>,
+    #[serde(default)]
+    pub terminal: bool,
+    /// File to append output of handlers not waited for to, instead of discarding it
+    pub log_file: Option<String>,
+    #[serde(flatten)]
+    pub process: ProcessOptions,
+}
+
+/// What a handler needs to be usable
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Requirement {
+    /// A graphical display
+    Gui,
+    /// A terminal on stdout
+    Tty,
 }
 
 const fn default_file_handler_wait() -> bool {
@@ -38,6 +61,8 @@ pub(crate) struct FileFilter {
     #[serde(default)]
     pub no_pipe: bool,
     pub stdin_arg: Option<String>,
+    #[serde(flatten)]
+    pub process: ProcessOptions,
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -45,76 +70,639 @@ pub(crate) struct SchemeHandler {
     pub command: String,
     #[serde(default)]
     pub shell: bool,
+    /// File to append output to, instead of discarding it
+    pub log_file: Option<String>,
+    /// Command outputs the URL content, which is dispatched to file handlers by MIME type
+    #[serde(default)]
+    pub fetch: bool,
+    #[serde(flatten)]
+    pub process: ProcessOptions,
+    /// Handlers for some URLs of the scheme, the first matching one is used instead of this handler
+    #[serde(default)]
+    pub rules: Vec<SchemeRule>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-pub(crate) struct Config {
+impl SchemeHandler {
+    /// Get handler of the first rule matching an URL, or this one
+    pub(crate) fn for_url(&self, url: &url::Url) -> &SchemeHandler {
+        self.rules
+            .iter()
+            .find(|r| r.matches(url))
+            .map_or(self, |r| &r.handler)
+    }
+}
+
+/// Handler for URLs matching a host glob and/or a path regex
+#[derive(Clone, Debug, serde::Deserialize)]
+pub(crate) struct SchemeRule {
+    #[serde(default, deserialize_with = "deserialize_host_glob")]
+    pub host: Option<regex::Regex>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub path: Option<regex::Regex>,
+    #[serde(flatten)]
+    pub handler: SchemeHandler,
+}
+
+impl SchemeRule {
+    pub(crate) fn matches(&self, url: &url::Url) -> bool {
+        self.host
+            .as_ref()
+            .is_none_or(|h| h.is_match(&url.host_str().unwrap_or_default().to_ascii_lowercase()))
+            && self.path.as_ref().is_none_or(|p| p.is_match(url.path()))
+    }
+}
+
+/// Deserialize a glob, where `*` matches any characters and `?` a single one, to an anchored lower case regex
+fn deserialize_host_glob<'de, D>(deserializer: D) -> Result<Option<regex::Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let glob = <String as serde::Deserialize>::deserialize(deserializer)?;
+    let pattern = glob
+        .to_ascii_lowercase()
+        .split('*')
+        .map(|p| {
+            p.split('?')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect::<Vec<_>>()
+        .join(".*");
+    regex::Regex::new(&format!("^{pattern}$"))
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid host glob {glob:?}: {e}")))
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<regex::Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let pattern = <String as serde::Deserialize>::deserialize(deserializer)?;
+    regex::Regex::new(&pattern)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid regex {pattern:?}: {e}")))
+}
+
+/// Process settings shared by handlers and filters
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Deserialize)]
+pub(crate) struct ProcessOptions {
+    /// Environment variables to set
     #[serde(default)]
-    pub filetype: HashMap<String, Filetype>,
+    pub env: HashMap<String, String>,
+    /// Working directory, with % patterns substituted
+    pub cwd: Option<String>,
+    /// Start from an empty environment instead of inheriting it
+    #[serde(default)]
+    pub clear_env: bool,
+    #[serde(default, deserialize_with = "deserialize_umask")]
+    pub umask: Option<u16>,
+    /// Copy stderr to the stderr log, and report its last lines if the command fails
+    #[serde(default)]
+    pub capture_stderr: bool,
+}
+
+fn deserialize_umask<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let umask = <u16 as serde::Deserialize>::deserialize(deserializer)?;
+    if umask > 0o777 {
+        return Err(serde::de::Error::custom(format!(
+            "invalid umask {umask:#o}, must be between 0o000 and 0o777"
+        )));
+    }
+    Ok(Some(umask))
+}
 
+/// Logging settings
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Deserialize)]
+pub(crate) struct LogConfig {
+    /// Maximum level of logged messages
+    #[serde(default, deserialize_with = "deserialize_log_level")]
+    pub level: Option<log::LevelFilter>,
+    #[serde(default)]
+    pub destination: LogDestination,
+    /// Log file for the file destination, defaults to one in the state directory
+    pub file: Option<PathBuf>,
+    /// Record each handler run in the activity log
     #[serde(default)]
+    pub activity: bool,
+    /// Activity log file, defaults to one in the state directory
+    pub activity_file: Option<PathBuf>,
+}
+
+/// Where log messages are written
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogDestination {
+    #[default]
+    Stderr,
+    File,
+    /// Systemd journal, with structured fields
+    Journal,
+}
+
+fn deserialize_log_level<'de, D>(deserializer: D) -> Result<Option<log::LevelFilter>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let level = <String as serde::Deserialize>::deserialize(deserializer)?;
+    log::LevelFilter::from_str(&level).map(Some).map_err(|_| {
+        serde::de::Error::custom(format!(
+            "invalid log level {level:?}, must be one of off, error, warn, info, debug or trace"
+        ))
+    })
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub filetype: HashMap<String, Filetype>,
+
     pub handler_preview: HashMap<String, FileHandler>,
     pub default_handler_preview: FileHandler,
 
-    #[serde(default)]
     pub handler_open: HashMap<String, FileHandler>,
     pub default_handler_open: FileHandler,
 
-    #[serde(default)]
     pub handler_edit: HashMap<String, FileHandler>,
 
-    #[serde(default)]
     pub filter: HashMap<String, FileFilter>,
 
-    #[serde(default)]
     pub handler_scheme: HashMap<String, SchemeHandler>,
+    /// Handler for URLs whose scheme has no handler
+    pub default_handler_scheme: Option<SchemeHandler>,
+
+    /// Command prefix to run terminal handlers in a new terminal window (ie. `foot -e`)
+    pub terminal_command: Option<String>,
+
+    pub log: LogConfig,
+
+    /// Directories in which project configs are applied
+    pub trusted_project_dirs: Vec<PathBuf>,
+
+    /// Files and drop-in directories the config was read from
+    pub sources: Vec<PathBuf>,
+
+    /// Where each entry was defined, by dotted key (ie. `handler_open.text`)
+    pub origins: HashMap<String, Origin>,
+}
+
+/// Location of a config entry
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Origin {
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} line {}", self.path, self.line)
+    }
+}
+
+impl Config {
+    /// Describe where an entry was defined, for error messages
+    pub(crate) fn describe(&self, key: &str) -> String {
+        match self.origins.get(key) {
+            Some(origin) => format!("{key} (defined in {origin})"),
+            None => key.to_owned(),
+        }
+    }
+
+    /// Return config with the closest trusted project config applied for target paths, if there is one they all share
+    ///
+    /// Project configs are searched in the directory of each target and its parents, or in the current directory if
+    /// there is no target, and can not themselves trust other directories.
+    pub(crate) fn with_project_config(&self, targets: &[PathBuf]) -> anyhow::Result<Option<Self>> {
+        let mut project_config_paths = if targets.is_empty() {
+            vec![project_config_path(Path::new("."))?]
+        } else {
+            targets
+                .iter()
+                .map(|t| project_config_path(t))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+        project_config_paths.sort_unstable();
+        project_config_paths.dedup();
+        let project_config_path = match project_config_paths.as_slice() {
+            [Some(path)] => path.to_owned(),
+            [None] => return Ok(None),
+            _ => {
+                log::info!("Paths are not all in the same project, ignoring project configs");
+                return Ok(None);
+            }
+        };
+        let project_dir = project_config_path
+            .parent()
+            .and_then(|d| fs::canonicalize(d).ok())
+            .ok_or_else(|| anyhow::anyhow!("Unable to get directory of {project_config_path:?}"))?;
+        let trusted = self
+            .trusted_project_dirs
+            .iter()
+            .any(|d| fs::canonicalize(d).is_ok_and(|d| project_dir.starts_with(d)));
+        if !trusted {
+            log::info!(
+                "Ignoring project config {project_config_path:?}, add its directory to trusted_project_dirs to apply it"
+            );
+            return Ok(None);
+        }
+        log::debug!("Applying project config {project_config_path:?}");
+
+        let mut builder = ConfigBuilder::from(self.clone());
+        builder
+            .read_file(&project_config_path)
+            .with_context(|| format!("Failed to apply project config {project_config_path:?}"))?;
+        builder
+            .trusted_project_dirs
+            .clone_from(&self.trusted_project_dirs);
+        Ok(Some(builder.build()?))
+    }
+}
+
+/// Config as read from a single file
+#[derive(Debug, serde::Deserialize)]
+struct ConfigFragment {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+
+    #[serde(default)]
+    trusted_project_dirs: Vec<PathBuf>,
+
+    terminal_command: Option<Spanned<String>>,
+
+    log: Option<Spanned<LogConfig>>,
+
+    #[serde(default)]
+    filetype: HashMap<String, Spanned<Filetype>>,
+
+    #[serde(default)]
+    handler_preview: HashMap<String, Spanned<FileHandler>>,
+    default_handler_preview: Option<Spanned<FileHandler>>,
+
+    #[serde(default)]
+    handler_open: HashMap<String, Spanned<FileHandler>>,
+    default_handler_open: Option<Spanned<FileHandler>>,
+
+    #[serde(default)]
+    handler_edit: HashMap<String, Spanned<FileHandler>>,
+
+    #[serde(default)]
+    filter: HashMap<String, Spanned<FileFilter>>,
+
+    #[serde(default)]
+    handler_scheme: HashMap<String, Spanned<SchemeHandler>>,
+    default_handler_scheme: Option<Spanned<SchemeHandler>>,
+}
+
+/// Config being built by merging fragments, later ones override entries of previous ones
+#[derive(Default)]
+struct ConfigBuilder {
+    filetype: HashMap<String, Filetype>,
+    handler_preview: HashMap<String, FileHandler>,
+    default_handler_preview: Option<FileHandler>,
+    handler_open: HashMap<String, FileHandler>,
+    default_handler_open: Option<FileHandler>,
+    handler_edit: HashMap<String, FileHandler>,
+    filter: HashMap<String, FileFilter>,
+    handler_scheme: HashMap<String, SchemeHandler>,
+    default_handler_scheme: Option<SchemeHandler>,
+    terminal_command: Option<String>,
+    log: LogConfig,
+    trusted_project_dirs: Vec<PathBuf>,
+    sources: Vec<PathBuf>,
+    origins: HashMap<String, Origin>,
+    /// Files currently being read, to detect include cycles
+    stack: Vec<PathBuf>,
 }
 
-pub(crate) fn parse_config() -> anyhow::Result<Config> {
-    parse_config_path(&get_config_path()?)
+/// Name of the directory next to the main config file, whose `*.toml` files are merged after it
+const DROPIN_DIRNAME: &str = "config.d";
+
+/// Name of config files overriding the user config for files in the same directory or below
+const PROJECT_CONFIG_FILENAME: &str = ".rsop.toml";
+
+const CONFIG_FILENAME: &str = "config.toml";
+
+const DEFAULT_CONFIG_STR: &str = include_str!("../config/config.toml.default");
+
+const ADVANCED_CONFIG_STR: &str = include_str!("../config/config.toml.advanced");
+
+fn xdg_dirs() -> xdg::BaseDirectories {
+    xdg::BaseDirectories::with_prefix(env!("CARGO_PKG_NAME"))
 }
 
-fn get_config_path() -> anyhow::Result<PathBuf> {
-    const CONFIG_FILENAME: &str = "config.toml";
-    const DEFAULT_CONFIG_STR: &str = include_str!("../config/config.toml.default");
-    let binary_name = env!("CARGO_PKG_NAME");
-    let xdg_dirs = xdg::BaseDirectories::with_prefix(binary_name);
-    let config_filepath = if let Some(p) = xdg_dirs.find_config_file(CONFIG_FILENAME) {
-        p
+/// Parse config from an explicit path, or from the XDG config directory, or use the built-in default one
+pub(crate) fn parse_config(path: Option<&Path>) -> anyhow::Result<Config> {
+    if let Some(path) = path {
+        log::debug!("Config filepath: {path:?}");
+        return parse_config_path(path);
+    }
+    let xdg_dirs = xdg_dirs();
+    if let Some(xdg_path) = xdg_dirs.find_config_file(CONFIG_FILENAME) {
+        log::debug!("Config filepath: {xdg_path:?}");
+        parse_config_path(&xdg_path)
     } else {
-        let path = xdg_dirs.place_config_file(CONFIG_FILENAME)?;
-        log::warn!("No config file found, creating a default one in {path:?}");
-        let mut file = File::create(&path)?;
-        file.write_all(DEFAULT_CONFIG_STR.as_bytes())?;
-        path
-    };
+        let xdg_path = xdg_dirs
+            .get_config_file(CONFIG_FILENAME)
+            .ok_or_else(|| anyhow::anyhow!("Unable to get config directory"))?;
+        log::debug!(
+            "No config file found in {xdg_path:?}, using built-in default config, run 'rsop --init-config' to create one"
+        );
+        parse_default_config(&xdg_path)
+    }
+}
 
-    log::debug!("Config filepath: {config_filepath:?}");
+/// Get path of the config file `parse_config` reads, or would read if it existed
+pub(crate) fn config_path(path: Option<&Path>) -> anyhow::Result<PathBuf> {
+    if let Some(path) = path {
+        return Ok(path.to_owned());
+    }
+    let xdg_dirs = xdg_dirs();
+    xdg_dirs
+        .find_config_file(CONFIG_FILENAME)
+        .or_else(|| xdg_dirs.get_config_file(CONFIG_FILENAME))
+        .ok_or_else(|| anyhow::anyhow!("Unable to get config directory"))
+}
 
-    Ok(config_filepath)
+/// Write a config file template, and return its path
+pub(crate) fn init_config(
+    path: Option<&Path>,
+    advanced: bool,
+    force: bool,
+) -> anyhow::Result<PathBuf> {
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => xdg_dirs().place_config_file(CONFIG_FILENAME)?,
+    };
+    anyhow::ensure!(
+        force || !path.exists(),
+        "Config file {path:?} already exists, use --force to overwrite it"
+    );
+    let template = if advanced {
+        ADVANCED_CONFIG_STR
+    } else {
+        DEFAULT_CONFIG_STR
+    };
+    fs::write(&path, template).with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(path)
 }
 
+/// Parse config file, along with its includes and drop-in directory
 fn parse_config_path(path: &Path) -> anyhow::Result<Config> {
-    let toml_data = std::fs::read_to_string(path)?;
-    log::trace!("Config data: {toml_data:?}");
-
-    let mut config: Config = toml::from_str(&toml_data)?;
-    // Normalize extensions to lower case
-    for filetype in config.filetype.values_mut() {
-        filetype.extensions = filetype
-            .extensions
-            .iter()
-            .map(|e| e.to_lowercase())
-            .collect();
-    }
+    let mut builder = ConfigBuilder::default();
+    builder.read_file(path)?;
+    builder.read_dropins(path)?;
+    let config = builder.build()?;
     log::trace!("Config: {config:?}");
+    Ok(config)
+}
 
+/// Parse built-in default config, along with the drop-in directory next to where the config file would be
+fn parse_default_config(path: &Path) -> anyhow::Result<Config> {
+    let mut builder = ConfigBuilder::default();
+    let fragment: ConfigFragment = toml::from_str(DEFAULT_CONFIG_STR)?;
+    builder.merge(
+        fragment,
+        Path::new("<built-in default config>"),
+        DEFAULT_CONFIG_STR,
+    );
+    // Watched by the daemon, to pick up the config when it gets created
+    builder.sources.push(path.to_owned());
+    builder.read_dropins(path)?;
+    let config = builder.build()?;
+    log::trace!("Config: {config:?}");
     Ok(config)
 }
 
+/// Resolve a path found in a config file, expanding `~` and making it relative to the config file directory
+fn resolve_path(dir: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rel_path) => env::var_os("HOME")
+            .map(|h| PathBuf::from(h).join(rel_path))
+            .ok_or_else(|| anyhow::anyhow!("HOME is not set, unable to expand {path:?}")),
+        Err(_) => Ok(dir.join(path)),
+    }
+}
+
+/// Find closest project config for a target path, regardless of trust
+fn project_config_path(target: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let target = env::current_dir()?.join(target);
+    let dir = if target.is_dir() {
+        target.as_path()
+    } else {
+        match target.parent() {
+            Some(dir) if dir.is_dir() => dir,
+            _ => return Ok(None),
+        }
+    };
+    Ok(find_project_config(dir))
+}
+
+/// Find closest project config in a directory or its parents
+fn find_project_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|d| d.join(PROJECT_CONFIG_FILENAME))
+        .find(|p| p.is_file())
+}
+
+impl From<Config> for ConfigBuilder {
+    fn from(config: Config) -> Self {
+        Self {
+            filetype: config.filetype,
+            handler_preview: config.handler_preview,
+            default_handler_preview: Some(config.default_handler_preview),
+            handler_open: config.handler_open,
+            default_handler_open: Some(config.default_handler_open),
+            handler_edit: config.handler_edit,
+            filter: config.filter,
+            handler_scheme: config.handler_scheme,
+            default_handler_scheme: config.default_handler_scheme,
+            terminal_command: config.terminal_command,
+            log: config.log,
+            trusted_project_dirs: config.trusted_project_dirs,
+            sources: config.sources,
+            origins: config.origins,
+            stack: Vec::new(),
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Read `*.toml` files of the drop-in directory next to the main config file, in lexical order
+    fn read_dropins(&mut self, config_path: &Path) -> anyhow::Result<()> {
+        let dropin_dir = config_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(DROPIN_DIRNAME);
+        if dropin_dir.is_dir() {
+            let mut dropin_paths: Vec<PathBuf> = fs::read_dir(&dropin_dir)
+                .with_context(|| format!("Failed to read directory {dropin_dir:?}"))?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<_, _>>()?;
+            dropin_paths.retain(|p| p.extension().is_some_and(|e| e == "toml") && p.is_file());
+            dropin_paths.sort_unstable();
+            for dropin_path in dropin_paths {
+                self.read_file(&dropin_path)?;
+            }
+        }
+        self.sources.push(dropin_dir);
+        Ok(())
+    }
+
+    /// Read a config file, and merge its includes first, and then its own entries
+    fn read_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let toml_data =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        log::debug!("Reading config file {path:?}");
+        log::trace!("Config data: {toml_data:?}");
+
+        let canonical_path = fs::canonicalize(path)?;
+        anyhow::ensure!(
+            !self.stack.contains(&canonical_path),
+            "Include cycle: {path:?} includes itself"
+        );
+        let fragment: ConfigFragment =
+            toml::from_str(&toml_data).with_context(|| format!("Failed to parse {path:?}"))?;
+        self.sources.push(path.to_owned());
+
+        // Included paths are relative to the including file
+        self.stack.push(canonical_path);
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for include in &fragment.include {
+            self.read_file(&resolve_path(dir, include)?)
+                .with_context(|| format!("Failed to include {include:?} from {path:?}"))?;
+        }
+        self.stack.pop();
+        for trusted_dir in &fragment.trusted_project_dirs {
+            self.trusted_project_dirs
+                .push(resolve_path(dir, trusted_dir)?);
+        }
+
+        self.merge(fragment, path, &toml_data);
+        Ok(())
+    }
+
+    /// Merge entries of a fragment over the current ones
+    fn merge(&mut self, fragment: ConfigFragment, path: &Path, toml_data: &str) {
+        let mut origins = Vec::new();
+        let mut origin = |key: String, span: Range<usize>| {
+            let line = toml_data
+                .bytes()
+                .take(span.start)
+                .filter(|b| *b == b'\n')
+                .count()
+                + 1;
+            origins.push((
+                key,
+                Origin {
+                    path: path.to_owned(),
+                    line,
+                },
+            ));
+        };
+
+        for (name, filetype) in fragment.filetype {
+            origin(format!("filetype.{name}"), filetype.span());
+            let mut filetype = filetype.into_inner();
+            // Normalize extensions to lower case
+            filetype.extensions = filetype
+                .extensions
+                .iter()
+                .map(|e| e.to_lowercase())
+                .collect();
+            self.filetype.insert(name, filetype);
+        }
+        for (section, src, dst) in [
+            (
+                "handler_preview",
+                fragment.handler_preview,
+                &mut self.handler_preview,
+            ),
+            (
+                "handler_open",
+                fragment.handler_open,
+                &mut self.handler_open,
+            ),
+            (
+                "handler_edit",
+                fragment.handler_edit,
+                &mut self.handler_edit,
+            ),
+        ] {
+            for (name, handler) in src {
+                origin(format!("{section}.{name}"), handler.span());
+                dst.insert(name, handler.into_inner());
+            }
+        }
+        for (name, filter) in fragment.filter {
+            origin(format!("filter.{name}"), filter.span());
+            self.filter.insert(name, filter.into_inner());
+        }
+        for (name, handler) in fragment.handler_scheme {
+            origin(format!("handler_scheme.{name}"), handler.span());
+            self.handler_scheme.insert(name, handler.into_inner());
+        }
+        for (section, src, dst) in [
+            (
+                "default_handler_preview",
+                fragment.default_handler_preview,
+                &mut self.default_handler_preview,
+            ),
+            (
+                "default_handler_open",
+                fragment.default_handler_open,
+                &mut self.default_handler_open,
+            ),
+        ] {
+            if let Some(handler) = src {
+                origin(section.to_owned(), handler.span());
+                *dst = Some(handler.into_inner());
+            }
+        }
+        if let Some(handler) = fragment.default_handler_scheme {
+            origin("default_handler_scheme".to_owned(), handler.span());
+            self.default_handler_scheme = Some(handler.into_inner());
+        }
+        if let Some(terminal_command) = fragment.terminal_command {
+            origin("terminal_command".to_owned(), terminal_command.span());
+            self.terminal_command = Some(terminal_command.into_inner());
+        }
+        if let Some(log) = fragment.log {
+            origin("log".to_owned(), log.span());
+            self.log = log.into_inner();
+        }
+
+        self.origins.extend(origins);
+    }
+
+    fn build(self) -> anyhow::Result<Config> {
+        Ok(Config {
+            filetype: self.filetype,
+            handler_preview: self.handler_preview,
+            default_handler_preview: self
+                .default_handler_preview
+                .ok_or_else(|| anyhow::anyhow!("Missing default_handler_preview section"))?,
+            handler_open: self.handler_open,
+            default_handler_open: self
+                .default_handler_open
+                .ok_or_else(|| anyhow::anyhow!("Missing default_handler_open section"))?,
+            handler_edit: self.handler_edit,
+            filter: self.filter,
+            handler_scheme: self.handler_scheme,
+            default_handler_scheme: self.default_handler_scheme,
+            terminal_command: self.terminal_command,
+            log: self.log,
+            trusted_project_dirs: self.trusted_project_dirs,
+            sources: self.sources,
+            origins: self.origins,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io;
+    use std::io::{self, Write as _};
 
     use super::*;
 
@@ -137,7 +725,11 @@ mod tests {
                 wait: true,
                 shell: false,
                 no_pipe: false,
-                stdin_arg: None
+                stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
         assert_eq!(config.handler_open.len(), 0);
@@ -148,7 +740,11 @@ mod tests {
                 wait: true,
                 shell: false,
                 no_pipe: false,
-                stdin_arg: None
+                stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
         assert_eq!(config.filter.len(), 0);
@@ -175,7 +771,11 @@ mod tests {
                 wait: true,
                 shell: false,
                 no_pipe: false,
-                stdin_arg: None
+                stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
         assert_eq!(config.handler_open.len(), 1);
@@ -186,7 +786,11 @@ mod tests {
                 wait: true,
                 shell: false,
                 no_pipe: false,
-                stdin_arg: None
+                stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
         assert_eq!(config.filter.len(), 1);
@@ -214,7 +818,11 @@ mod tests {
                 wait: true,
                 shell: true,
                 no_pipe: false,
-                stdin_arg: Some(String::new())
+                stdin_arg: Some(String::new()),
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
         assert_eq!(config.handler_open.len(), 20);
@@ -225,7 +833,11 @@ mod tests {
                 wait: true,
                 shell: true,
                 no_pipe: false,
-                stdin_arg: Some(String::new())
+                stdin_arg: Some(String::new()),
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
         assert_eq!(config.filter.len(), 5);
@@ -331,6 +943,7 @@ wait = false
 shell = true
 no_pipe = true
 stdin_arg = "/dev/stdin"
+requires = "tty"
 "#;
         let mut config_file = tempfile::NamedTempFile::new().unwrap();
         config_file.write_all(toml.as_bytes()).unwrap();
@@ -345,10 +958,167 @@ stdin_arg = "/dev/stdin"
                 shell: true,
                 no_pipe: true,
                 stdin_arg: Some("/dev/stdin".to_owned()),
+                requires: Some(Requirement::Tty),
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             }
         );
     }
 
+    #[test]
+    fn handler_process_options() {
+        let toml = r#"
+[default_handler_preview]
+command = "file %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[filter.gz]
+command = "zcat %i"
+clear_env = true
+
+[handler_preview.text]
+command = "less %i"
+env = { LESS = "-R", TERM = "xterm-256color" }
+cwd = "%d"
+umask = 0o077
+
+[handler_scheme.https]
+command = "firefox %i"
+env = { MOZ_ENABLE_WAYLAND = "1" }
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let config = parse_config_path(config_file.path()).unwrap();
+        assert_eq!(
+            config.handler_preview.get("text").unwrap().process,
+            ProcessOptions {
+                env: HashMap::from([
+                    ("LESS".to_owned(), "-R".to_owned()),
+                    ("TERM".to_owned(), "xterm-256color".to_owned()),
+                ]),
+                cwd: Some("%d".to_owned()),
+                clear_env: false,
+                umask: Some(0o077),
+                capture_stderr: false,
+            }
+        );
+        assert_eq!(
+            config.filter.get("gz").unwrap().process,
+            ProcessOptions {
+                clear_env: true,
+                ..ProcessOptions::default()
+            }
+        );
+        assert_eq!(
+            config.handler_scheme.get("https").unwrap().process.env,
+            HashMap::from([("MOZ_ENABLE_WAYLAND".to_owned(), "1".to_owned())])
+        );
+        assert_eq!(
+            config.default_handler_open.process,
+            ProcessOptions::default()
+        );
+    }
+
+    #[test]
+    fn terminal_handler() {
+        let toml = r#"
+terminal_command = "foot -e"
+
+[default_handler_preview]
+command = "file %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[filetype.text]
+mimes = ["text"]
+
+[handler_open.text]
+command = "less %i"
+terminal = true
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let config = parse_config_path(config_file.path()).unwrap();
+        assert_eq!(config.terminal_command.as_deref(), Some("foot -e"));
+        assert!(config.handler_open.get("text").unwrap().terminal);
+        assert!(!config.default_handler_open.terminal);
+        assert_eq!(config.origins.get("terminal_command").unwrap().line, 2);
+    }
+
+    #[test]
+    fn handler_invalid_umask() {
+        let toml = r#"
+[default_handler_preview]
+command = "file %i"
+umask = 0o1777
+
+[default_handler_open]
+command = "cat %i"
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let err = parse_config_path(config_file.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid umask 0o1777"));
+    }
+
+    #[test]
+    fn log_config() {
+        let toml = r#"
+[default_handler_preview]
+command = "file %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[log]
+level = "debug"
+destination = "file"
+file = "/tmp/rsop.log"
+activity = true
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let config = parse_config_path(config_file.path()).unwrap();
+        assert_eq!(
+            config.log,
+            LogConfig {
+                level: Some(log::LevelFilter::Debug),
+                destination: LogDestination::File,
+                file: Some(PathBuf::from("/tmp/rsop.log")),
+                activity: true,
+                activity_file: None,
+            }
+        );
+        assert_eq!(config.origins.get("log").unwrap().line, 8);
+    }
+
+    #[test]
+    fn log_config_invalid_level() {
+        let toml = r#"
+[default_handler_preview]
+command = "file %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[log]
+level = "verbose"
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let err = parse_config_path(config_file.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid log level \"verbose\""));
+    }
+
     #[test]
     fn extension_normalization() {
         let toml = r#"
@@ -504,6 +1274,93 @@ shell = true
         assert!(https.shell);
     }
 
+    #[test]
+    fn scheme_rules() {
+        let toml = r#"
+[default_handler_preview]
+command = "cat %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[handler_scheme.https]
+command = "firefox %i"
+
+[[handler_scheme.https.rules]]
+host = "*.GitHub.com"
+command = "firefox -P work %i"
+
+[[handler_scheme.https.rules]]
+host = "youtube.com"
+path = "^/watch"
+command = "mpv %i"
+
+[[handler_scheme.https.rules]]
+path = "\\.pdf$"
+command = "zathura %i"
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let config = parse_config_path(config_file.path()).unwrap();
+        let handler = config.handler_scheme.get("https").unwrap();
+        assert_eq!(handler.rules.len(), 3);
+        let command = |url: &str| {
+            handler
+                .for_url(&url::Url::parse(url).unwrap())
+                .command
+                .clone()
+        };
+        assert_eq!(command("https://api.github.com/x"), "firefox -P work %i");
+        assert_eq!(command("https://github.com/x"), "firefox %i");
+        assert_eq!(command("https://youtube.com/watch?v=1"), "mpv %i");
+        assert_eq!(command("https://youtube.com/feed"), "firefox %i");
+        assert_eq!(command("https://youtube.com/a.pdf"), "zathura %i");
+        assert_eq!(command("https://example.com/"), "firefox %i");
+    }
+
+    #[test]
+    fn scheme_rules_invalid_regex() {
+        let toml = r#"
+[default_handler_preview]
+command = "cat %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[handler_scheme.https]
+command = "firefox %i"
+
+[[handler_scheme.https.rules]]
+path = "^/(watch"
+command = "mpv %i"
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let err = parse_config_path(config_file.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid regex \"^/(watch\""));
+    }
+
+    #[test]
+    fn default_handler_scheme() {
+        let toml = r#"
+[default_handler_preview]
+command = "cat %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[default_handler_scheme]
+command = "firefox %i"
+"#;
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(toml.as_bytes()).unwrap();
+
+        let config = parse_config_path(config_file.path()).unwrap();
+        assert_eq!(config.default_handler_scheme.unwrap().command, "firefox %i");
+    }
+
     #[test]
     fn handler_edit_config() {
         let toml = r#"
@@ -591,4 +1448,311 @@ wait = false
         let audio_handler = config.handler_open.get("audio").unwrap();
         assert!(!audio_handler.wait);
     }
+
+    const BASE_CONFIG_STR: &str = r#"
+[default_handler_preview]
+command = "file %i"
+
+[default_handler_open]
+command = "cat %i"
+
+[filetype.text]
+mimes = ["text"]
+
+[handler_preview.text]
+command = "head %i"
+"#;
+
+    #[test]
+    fn include_overridden_by_including_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("base.toml"), BASE_CONFIG_STR).unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            r#"
+include = ["base.toml"]
+
+[handler_preview.text]
+command = "bat %i"
+"#,
+        )
+        .unwrap();
+
+        let config = parse_config_path(&config_path).unwrap();
+        assert_eq!(config.default_handler_preview.command, "file %i");
+        assert_eq!(
+            config.handler_preview.get("text").unwrap().command,
+            "bat %i"
+        );
+        assert_eq!(
+            config.origins.get("handler_preview.text").unwrap(),
+            &Origin {
+                path: config_path.clone(),
+                line: 4
+            }
+        );
+        assert_eq!(
+            config.origins.get("filetype.text").unwrap(),
+            &Origin {
+                path: dir.path().join("base.toml"),
+                line: 8
+            }
+        );
+        assert_eq!(
+            config.sources,
+            vec![
+                config_path,
+                dir.path().join("base.toml"),
+                dir.path().join(DROPIN_DIRNAME)
+            ]
+        );
+    }
+
+    #[test]
+    fn dropin_dir_lexical_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, BASE_CONFIG_STR).unwrap();
+        let dropin_dir = dir.path().join(DROPIN_DIRNAME);
+        fs::create_dir(&dropin_dir).unwrap();
+        fs::write(
+            dropin_dir.join("20-local.toml"),
+            "[handler_preview.text]\ncommand = \"less %i\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dropin_dir.join("10-team.toml"),
+            "[handler_preview.text]\ncommand = \"bat %i\"\n[handler_open.text]\ncommand = \"vim %i\"\n",
+        )
+        .unwrap();
+        fs::write(dropin_dir.join("ignored.txt"), "not toml [[[").unwrap();
+
+        let config = parse_config_path(&config_path).unwrap();
+        assert_eq!(
+            config.handler_preview.get("text").unwrap().command,
+            "less %i"
+        );
+        assert_eq!(config.handler_open.get("text").unwrap().command, "vim %i");
+        assert_eq!(
+            config.origins.get("handler_preview.text").unwrap().path,
+            dropin_dir.join("20-local.toml")
+        );
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+        fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]\n").unwrap();
+
+        let err = parse_config_path(&dir.path().join("a.toml")).unwrap_err();
+        assert!(format!("{err:#}").contains("cycle"));
+    }
+
+    #[test]
+    fn include_error_reports_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("bad.toml"), "[handler_open.text\n").unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "include = [\"bad.toml\"]\n").unwrap();
+
+        let err = parse_config_path(&config_path).unwrap_err();
+        assert!(err.downcast_ref::<toml::de::Error>().is_some());
+        let msg = format!("{err:#}");
+        assert!(msg.contains("bad.toml"));
+        assert!(msg.contains("line 1"));
+    }
+
+    #[test]
+    fn describe_entry() {
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        config_file.write_all(BASE_CONFIG_STR.as_bytes()).unwrap();
+
+        let config = parse_config_path(config_file.path()).unwrap();
+        assert_eq!(
+            config.describe("handler_preview.text"),
+            format!(
+                "handler_preview.text (defined in {:?} line 11)",
+                config_file.path()
+            )
+        );
+        assert_eq!(config.describe("filter.text"), "filter.text");
+    }
+
+    #[test]
+    fn project_config_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!("trusted_project_dirs = [\"work\"]\n{BASE_CONFIG_STR}"),
+        )
+        .unwrap();
+        let project_dir = dir.path().join("work").join("repo");
+        fs::create_dir_all(project_dir.join("logs")).unwrap();
+        fs::write(
+            project_dir.join(PROJECT_CONFIG_FILENAME),
+            "trusted_project_dirs = [\"/\"]\n[handler_preview.text]\ncommand = \"logview %i\"\n",
+        )
+        .unwrap();
+        let target = project_dir.join("logs").join("a.log");
+        fs::write(&target, "").unwrap();
+
+        let config = parse_config_path(&config_path).unwrap();
+        let project_config = config
+            .with_project_config(std::slice::from_ref(&target))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            project_config.handler_preview.get("text").unwrap().command,
+            "logview %i"
+        );
+        assert_eq!(
+            project_config.default_handler_preview.command,
+            config.default_handler_preview.command
+        );
+        assert_eq!(
+            project_config.trusted_project_dirs,
+            vec![dir.path().join("work")]
+        );
+        assert_eq!(
+            project_config
+                .origins
+                .get("handler_preview.text")
+                .unwrap()
+                .path,
+            project_dir.join(PROJECT_CONFIG_FILENAME)
+        );
+    }
+
+    #[test]
+    fn project_config_multiple_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!("trusted_project_dirs = [\"work\"]\n{BASE_CONFIG_STR}"),
+        )
+        .unwrap();
+        let mut targets = Vec::new();
+        for project in ["a", "b"] {
+            let project_dir = dir.path().join("work").join(project);
+            fs::create_dir_all(&project_dir).unwrap();
+            fs::write(
+                project_dir.join(PROJECT_CONFIG_FILENAME),
+                format!("[handler_preview.text]\ncommand = \"{project} %i\"\n"),
+            )
+            .unwrap();
+            targets.push(project_dir.join("x.txt"));
+        }
+        targets.push(dir.path().join("work").join("a").join("y.txt"));
+
+        let config = parse_config_path(&config_path).unwrap();
+        let project_config = config
+            .with_project_config(&[targets[0].clone(), targets[2].clone()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            project_config.handler_preview.get("text").unwrap().command,
+            "a %i"
+        );
+        assert!(config.with_project_config(&targets).unwrap().is_none());
+        assert!(
+            config
+                .with_project_config(&[targets[0].clone(), config_path])
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn project_config_untrusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!("trusted_project_dirs = [\"work\"]\n{BASE_CONFIG_STR}"),
+        )
+        .unwrap();
+        let project_dir = dir.path().join("elsewhere");
+        fs::create_dir(&project_dir).unwrap();
+        fs::write(
+            project_dir.join(PROJECT_CONFIG_FILENAME),
+            "[handler_preview.text]\ncommand = \"evil %i\"\n",
+        )
+        .unwrap();
+
+        let config = parse_config_path(&config_path).unwrap();
+        assert!(
+            config
+                .with_project_config(&[project_dir.join("a.txt")])
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            config
+                .with_project_config(std::slice::from_ref(&project_dir))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn config_path_explicit() {
+        let path = Path::new("/etc/rsop/config.toml");
+        assert_eq!(config_path(Some(path)).unwrap(), path);
+        assert!(
+            config_path(None)
+                .unwrap()
+                .ends_with(Path::new("rsop").join(CONFIG_FILENAME))
+        );
+    }
+
+    #[test]
+    fn init_config_no_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+
+        assert_eq!(
+            init_config(Some(&config_path), false, false).unwrap(),
+            config_path
+        );
+        assert_eq!(
+            fs::read_to_string(&config_path).unwrap(),
+            DEFAULT_CONFIG_STR
+        );
+
+        let err = init_config(Some(&config_path), true, false).unwrap_err();
+        assert!(err.to_string().contains("--force"));
+        assert_eq!(
+            fs::read_to_string(&config_path).unwrap(),
+            DEFAULT_CONFIG_STR
+        );
+
+        init_config(Some(&config_path), true, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(&config_path).unwrap(),
+            ADVANCED_CONFIG_STR
+        );
+    }
+
+    #[test]
+    fn default_config_with_dropin() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let dropin_dir = dir.path().join(DROPIN_DIRNAME);
+        fs::create_dir(&dropin_dir).unwrap();
+        fs::write(
+            dropin_dir.join("local.toml"),
+            "[handler_open.text]\ncommand = \"bat %i\"\n",
+        )
+        .unwrap();
+
+        let config = parse_default_config(&config_path).unwrap();
+        assert!(!config_path.exists());
+        assert_eq!(config.default_handler_open.command, "cat -A %i");
+        assert_eq!(config.handler_open.get("text").unwrap().command, "bat %i");
+        assert_eq!(config.sources[0], config_path);
+    }
 }
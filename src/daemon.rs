@@ -0,0 +1,652 @@
+//! Long lived server process keeping the handler mapping in memory, and client side forwarding to it
+//!
+//! The client sends its arguments, environment and working directory, along with its stdin/stdout/stderr file
+//! descriptors. The daemon forks a worker process for each request, which takes over these descriptors and runs the
+//! usual dispatch logic, so terminal size probing and handler output behave as if the client had run them.
+//!
+//! Requests are only served if the client uses the same config file as the daemon, otherwise the client handles them
+//! itself. Project configs are applied by workers for each request.
+//!
+//! The config files are watched with inotify, and the handler mapping is rebuilt when they change.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, IoSlice, IoSliceMut, Read as _, Write as _},
+    os::{
+        fd::{AsFd as _, AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    str::FromStr as _,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use clap::Parser as _;
+use nix::{
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+    sys::{
+        inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+        signal::{Signal, killpg},
+        socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg},
+        wait::{WaitPidFlag, WaitStatus, waitpid},
+    },
+    unistd::{ForkResult, Pid, dup2_stderr, dup2_stdin, dup2_stdout, fork, setpgid},
+};
+
+use crate::{RsopMode, cli, config, handler::HandlerMapping, logger};
+
+const SOCKET_FILENAME: &str = "daemon.sock";
+
+/// Max size of a serialized request, to avoid allocating unbounded memory on garbage input
+const MAX_REQUEST_LEN: u32 = 1024 * 1024;
+
+/// How long to wait for a client to send its request, so a stuck client can not block the others
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to reap worker processes if nothing else wakes us up, in milliseconds
+const REAP_INTERVAL_MS: u16 = 5000;
+
+/// Request sent by a client, along with its stdin/stdout/stderr file descriptors
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Request {
+    mode: String,
+    /// Config path, canonicalized
+    config: PathBuf,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    cwd: PathBuf,
+}
+
+/// Worker process serving a client
+struct Worker {
+    pid: Pid,
+    /// Connection to the client, until it hangs up
+    client: Option<UnixStream>,
+}
+
+/// Inotify watches on the directories of config files
+///
+/// Directories are watched rather than the files themselves, because many editors save by writing a new file and
+/// renaming it over the old one.
+struct ConfigWatch {
+    inotify: Inotify,
+    /// Config file names to look for, by watched directory
+    filenames: HashMap<WatchDescriptor, HashSet<OsString>>,
+    /// Drop-in directories, where any `.toml` file is relevant
+    dropin_dirs: HashSet<WatchDescriptor>,
+}
+
+fn xdg_dirs() -> xdg::BaseDirectories {
+    xdg::BaseDirectories::with_prefix(env!("CARGO_PKG_NAME"))
+}
+
+/// Forward invocation to a running daemon, and return exit code, or `None` if no daemon is available for this config
+pub(crate) fn forward(mode: &RsopMode, config_path: Option<&Path>) -> anyhow::Result<Option<i32>> {
+    let Ok(socket_path) = xdg_dirs().get_runtime_file(SOCKET_FILENAME) else {
+        return Ok(None);
+    };
+    let mut stream = match UnixStream::connect(&socket_path) {
+        Ok(s) => s,
+        Err(err) => {
+            log::debug!("No daemon listening on {socket_path:?}: {err}");
+            return Ok(None);
+        }
+    };
+    let Some(request) = Request::current(mode, config_path) else {
+        log::debug!("Arguments or environment are not valid UTF-8, not forwarding to daemon");
+        return Ok(None);
+    };
+    log::debug!("Forwarding to daemon on {socket_path:?}");
+
+    let fds: [RawFd; 3] = [
+        io::stdin().as_raw_fd(),
+        io::stdout().as_raw_fd(),
+        io::stderr().as_raw_fd(),
+    ];
+    send_request(&mut stream, &request, fds)?;
+
+    let mut accepted = [0; 1];
+    stream
+        .read_exact(&mut accepted)
+        .context("Daemon closed connection before accepting request")?;
+    if accepted[0] == 0 {
+        log::debug!("Daemon uses a different config, not forwarding to it");
+        return Ok(None);
+    }
+
+    // Wait for worker exit code
+    let mut code = [0; 4];
+    stream
+        .read_exact(&mut code)
+        .context("Daemon closed connection before sending exit code")?;
+    Ok(Some(i32::from_le_bytes(code)))
+}
+
+/// Serve requests until killed
+pub(crate) fn serve(
+    config_path: Option<&Path>,
+    cfg: config::Config,
+    handlers: HandlerMapping,
+) -> anyhow::Result<()> {
+    let socket_path = xdg_dirs().place_runtime_file(SOCKET_FILENAME)?;
+    if socket_path.exists() {
+        anyhow::ensure!(
+            UnixStream::connect(&socket_path).is_err(),
+            "A daemon is already listening on {socket_path:?}"
+        );
+        log::debug!("Removing stale socket {socket_path:?}");
+        fs::remove_file(&socket_path)?;
+    }
+    let listener = UnixListener::bind(&socket_path)
+        .with_context(|| format!("Failed to bind socket {socket_path:?}"))?;
+    log::info!("Listening on {socket_path:?}");
+
+    // Clients may resolve a different default config path, ie. if their XDG_CONFIG_HOME differs, so compare paths
+    let config_key = canonical_config_path(&config::config_path(config_path)?);
+    let mut cfg = cfg;
+    let mut handlers = handlers;
+    let mut watch = ConfigWatch::new(&cfg.sources)?;
+    let mut workers: Vec<Worker> = Vec::new();
+    loop {
+        // Wait for new connections, config changes, or hang up of clients being served
+        let (listener_ready, watch_ready, hung_up) = {
+            let mut fds: Vec<PollFd> = Vec::with_capacity(workers.len() + 2);
+            fds.push(PollFd::new(listener.as_fd(), PollFlags::POLLIN));
+            fds.push(PollFd::new(watch.inotify.as_fd(), PollFlags::POLLIN));
+            let clients: Vec<_> = workers
+                .iter()
+                .filter_map(|w| w.client.as_ref().map(|c| (w.pid, c)))
+                .collect();
+            fds.extend(
+                clients
+                    .iter()
+                    .map(|(_, c)| PollFd::new(c.as_fd(), PollFlags::POLLIN)),
+            );
+            match poll(&mut fds, PollTimeout::from(REAP_INTERVAL_MS)) {
+                Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+                Err(err) => return Err(err).context("Failed to poll"),
+            }
+            let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+            let hung_up: Vec<Pid> = fds
+                .iter()
+                .skip(2)
+                .zip(&clients)
+                .filter(|(fd, _)| ready(fd))
+                .map(|(_, (pid, _))| *pid)
+                .collect();
+            (ready(&fds[0]), ready(&fds[1]), hung_up)
+        };
+
+        reap(&mut workers);
+
+        // Swap mapping only if the new config is valid, so a typo while editing does not break previews
+        if watch_ready && watch.changed() {
+            log::info!("Config changed, reloading");
+            match reload(config_path) {
+                Ok((new_cfg, new_handlers)) => {
+                    log::debug!("{new_handlers:?}");
+                    cfg = new_cfg;
+                    handlers = new_handlers;
+                    match ConfigWatch::new(&cfg.sources) {
+                        Ok(new_watch) => watch = new_watch,
+                        Err(err) => log::error!("Failed to watch new config files: {err:#}"),
+                    }
+                }
+                Err(err) => log::error!("Failed to reload config, keeping previous one: {err:#}"),
+            }
+        }
+
+        // Clients never write after their request, so readiness means they went away (ie. preview was cancelled),
+        // kill whatever is still running on their behalf
+        for worker in workers.iter_mut().filter(|w| hung_up.contains(&w.pid)) {
+            worker.client = None;
+            log::debug!("Client of worker {} hung up, killing it", worker.pid);
+            if let Err(err) = killpg(worker.pid, Signal::SIGTERM) {
+                log::warn!("Failed to kill worker {}: {err}", worker.pid);
+            }
+        }
+
+        if listener_ready {
+            match listener.accept() {
+                Ok((client, _)) => match accept(client, &config_key, &cfg, &handlers) {
+                    Ok(Some(worker)) => workers.push(worker),
+                    Ok(None) => {}
+                    Err(err) => log::error!("Failed to serve request: {err:#}"),
+                },
+                Err(err) => log::warn!("Failed to accept connection: {err}"),
+            }
+        }
+    }
+}
+
+/// Parse config and build a new handler mapping from it
+fn reload(config_path: Option<&Path>) -> anyhow::Result<(config::Config, HandlerMapping)> {
+    let cfg = config::parse_config(config_path).context("Failed to read config")?;
+    // Validate the whole config before applying any of it, so an invalid one leaves the daemon unchanged
+    let handlers = HandlerMapping::new(&cfg).context("Failed to build handler mapping")?;
+    logger::configure(&cfg.log).context("Failed to configure logging")?;
+    Ok((cfg, handlers))
+}
+
+/// Reap exited worker processes
+fn reap(workers: &mut Vec<Worker>) {
+    workers.retain(|w| {
+        !matches!(
+            waitpid(w.pid, Some(WaitPidFlag::WNOHANG)),
+            Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)) | Err(_)
+        )
+    });
+}
+
+/// Make config paths comparable between client and daemon
+fn canonical_config_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Read request from client and fork a worker process to serve it, if it uses the same config
+fn accept(
+    mut client: UnixStream,
+    config_key: &Path,
+    cfg: &config::Config,
+    handlers: &HandlerMapping,
+) -> anyhow::Result<Option<Worker>> {
+    let (request, fds) = recv_request(&mut client)?;
+    log::debug!(
+        "Request: mode={}, args={:?}, cwd={:?}",
+        request.mode,
+        request.args,
+        request.cwd
+    );
+    let accepted = request.config == config_key;
+    client.write_all(&[u8::from(accepted)])?;
+    if !accepted {
+        log::debug!("Client uses config {:?}, declining request", request.config);
+        return Ok(None);
+    }
+
+    // SAFETY:
+    // The daemon is single threaded, so the child is free to do anything the parent could
+    match unsafe { fork() }.context("Failed to fork worker")? {
+        ForkResult::Parent { child } => Ok(Some(Worker {
+            pid: child,
+            client: Some(client),
+        })),
+        ForkResult::Child => {
+            let code = match work(request, fds, cfg, handlers) {
+                Ok(()) => 0,
+                Err(err) => {
+                    eprintln!("Error: {err:?}");
+                    1
+                }
+            };
+            let _ = io::stdout().flush();
+            let _ = client.write_all(&i32::to_le_bytes(code));
+            process::exit(code);
+        }
+    }
+}
+
+/// Send request size along with stdio file descriptors, and then the request itself
+fn send_request(stream: &mut UnixStream, request: &Request, fds: [RawFd; 3]) -> anyhow::Result<()> {
+    let payload = serde_json::to_vec(request)?;
+    let payload_len = u32::try_from(payload.len())?;
+    anyhow::ensure!(payload_len <= MAX_REQUEST_LEN, "Request is too large");
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&payload_len.to_le_bytes())],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .context("Failed to send file descriptors to daemon")?;
+    stream.write_all(&payload)?;
+    Ok(())
+}
+
+/// Receive request and stdio file descriptors sent by `send_request`
+fn recv_request(client: &mut UnixStream) -> anyhow::Result<(Request, [OwnedFd; 3])> {
+    client.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let (payload_len, fds) = recv_fds(client)?;
+    anyhow::ensure!(
+        payload_len <= MAX_REQUEST_LEN,
+        "Request is too large ({payload_len} bytes)"
+    );
+    let mut payload = vec![0; usize::try_from(payload_len)?];
+    client.read_exact(&mut payload)?;
+    let request: Request = serde_json::from_slice(&payload).context("Invalid request")?;
+    Ok((request, fds))
+}
+
+/// Receive request size and stdio file descriptors
+fn recv_fds(client: &UnixStream) -> anyhow::Result<(u32, [OwnedFd; 3])> {
+    let mut len_buf = [0; 4];
+    let mut iov = [IoSliceMut::new(&mut len_buf)];
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; 3]);
+    let (bytes, raw_fds) = {
+        let msg = recvmsg::<()>(
+            client.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .context("Failed to receive file descriptors")?;
+        let mut raw_fds = Vec::new();
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                raw_fds.extend(fds);
+            }
+        }
+        (msg.bytes, raw_fds)
+    };
+    let owned_fds: Vec<OwnedFd> = raw_fds
+        .into_iter()
+        // SAFETY:
+        // These file descriptors were just received, and are owned by nothing else
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+    let fds: [OwnedFd; 3] = owned_fds
+        .try_into()
+        .map_err(|fds: Vec<_>| anyhow::anyhow!("Expected 3 file descriptors, got {}", fds.len()))?;
+    anyhow::ensure!(bytes == len_buf.len(), "Truncated request header");
+    Ok((u32::from_le_bytes(len_buf), fds))
+}
+
+/// Serve a request in a worker process
+fn work(
+    request: Request,
+    fds: [OwnedFd; 3],
+    cfg: &config::Config,
+    handlers: &HandlerMapping,
+) -> anyhow::Result<()> {
+    // Put the worker and its handlers in their own process group, so they can be killed together
+    setpgid(Pid::from_raw(0), Pid::from_raw(0))?;
+
+    // Take over client stdio
+    let [stdin, stdout, stderr] = fds;
+    dup2_stdin(stdin)?;
+    dup2_stdout(stdout)?;
+    dup2_stderr(stderr)?;
+
+    // Take over client environment
+    env::set_current_dir(&request.cwd)
+        .with_context(|| format!("Failed to change directory to {:?}", request.cwd))?;
+    let current_env: Vec<OsString> = env::vars_os().map(|(k, _)| k).collect();
+    for key in current_env {
+        // SAFETY:
+        // Forked worker is single threaded
+        unsafe {
+            env::remove_var(key);
+        }
+    }
+    for (key, value) in request.env {
+        // SAFETY:
+        // Forked worker is single threaded
+        unsafe {
+            env::set_var(key, value);
+        }
+    }
+
+    let mode = RsopMode::from_str(&request.mode)
+        .with_context(|| format!("Unexpected mode {:?}", request.mode))?;
+    let mut cl_opts = cli::CommandLineOpts::try_parse_from(request.args)?;
+    logger::set_overrides(crate::log_overrides(&mode, &cl_opts))?;
+    logger::configure(&cfg.log).context("Failed to configure logging")?;
+    crate::read_paths(&mode, &mut cl_opts)?;
+    let project_handlers = cfg
+        .with_project_config(&cl_opts.paths)?
+        .map(|c| HandlerMapping::new(&c).context("Failed to build handler mapping"))
+        .transpose()?;
+    crate::run(
+        &mode,
+        &cl_opts,
+        project_handlers.as_ref().unwrap_or(handlers),
+    )
+}
+
+impl ConfigWatch {
+    fn new(sources: &[PathBuf]) -> anyhow::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let flags = AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_CREATE;
+        let mut filenames: HashMap<WatchDescriptor, HashSet<OsString>> = HashMap::new();
+        let mut dropin_dirs = HashSet::new();
+        // Also watch symlink targets, for configs managed by dotfile tools
+        let paths: HashSet<PathBuf> = sources
+            .iter()
+            .flat_map(|p| [Some(p.to_owned()), fs::canonicalize(p).ok()])
+            .flatten()
+            .collect();
+        for path in paths {
+            let (Some(dir), Some(filename)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            if !dir.is_dir() {
+                log::debug!("Not watching {path:?}, its directory does not exist");
+                continue;
+            }
+            let wd = inotify
+                .add_watch(dir, flags)
+                .with_context(|| format!("Failed to watch directory {dir:?}"))?;
+            log::debug!("Watching {path:?}");
+            filenames.entry(wd).or_default().insert(filename.to_owned());
+            if path.is_dir() {
+                let dropin_wd = inotify
+                    .add_watch(&path, flags)
+                    .with_context(|| format!("Failed to watch directory {path:?}"))?;
+                dropin_dirs.insert(dropin_wd);
+            }
+        }
+        Ok(Self {
+            inotify,
+            filenames,
+            dropin_dirs,
+        })
+    }
+
+    /// Consume pending events, and return true if any config file changed
+    fn changed(&self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.inotify.read_events() {
+                Ok(events) => {
+                    changed |= events.iter().any(|e| {
+                        // A file being created is followed by a write, except for directories
+                        let created_file = e.mask.contains(AddWatchFlags::IN_CREATE)
+                            && !e.mask.contains(AddWatchFlags::IN_ISDIR);
+                        !created_file
+                            && e.name.as_ref().is_some_and(|n| {
+                                self.filenames.get(&e.wd).is_some_and(|f| f.contains(n))
+                                    || (self.dropin_dirs.contains(&e.wd)
+                                        && Path::new(n).extension().is_some_and(|x| x == "toml"))
+                            })
+                    });
+                }
+                Err(nix::errno::Errno::EAGAIN) => break,
+                Err(err) => {
+                    log::warn!("Failed to read inotify events: {err}");
+                    break;
+                }
+            }
+        }
+        changed
+    }
+}
+
+impl Request {
+    /// Build request for the current process, if all parameters can be represented as UTF-8
+    fn current(mode: &RsopMode, config_path: Option<&Path>) -> Option<Self> {
+        Some(Self {
+            mode: mode.to_string(),
+            config: canonical_config_path(&config::config_path(config_path).ok()?),
+            args: env::args_os()
+                .map(OsString::into_string)
+                .collect::<Result<_, _>>()
+                .ok()?,
+            env: env::vars_os()
+                .map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+                .collect::<Option<_>>()?,
+            cwd: env::current_dir().ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn request() -> Request {
+        Request {
+            mode: "preview".to_owned(),
+            config: PathBuf::from("/etc/rsop/config.toml"),
+            args: vec!["rsp".to_owned(), "a b.txt".to_owned()],
+            env: vec![("TERM".to_owned(), "xterm".to_owned())],
+            cwd: PathBuf::from("/tmp"),
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let tmp_file = tempfile::tempfile().unwrap();
+        let fds = [tmp_file.as_raw_fd(); 3];
+
+        send_request(&mut client, &request(), fds).unwrap();
+        let (received, received_fds) = recv_request(&mut server).unwrap();
+
+        assert_eq!(received, request());
+        let mut file = File::from(received_fds.into_iter().next().unwrap());
+        file.write_all(b"data").unwrap();
+        assert_eq!(tmp_file.metadata().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn request_too_large() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let fds = [io::stdin().as_raw_fd(); 3];
+        sendmsg::<()>(
+            client.as_raw_fd(),
+            &[IoSlice::new(&(MAX_REQUEST_LEN + 1).to_le_bytes())],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+
+        let err = recv_request(&mut server).unwrap_err();
+        assert!(err.to_string().contains("Request is too large"));
+
+        let mut large = request();
+        large
+            .args
+            .push("a".repeat(usize::try_from(MAX_REQUEST_LEN).unwrap()));
+        let (mut client2, _server2) = UnixStream::pair().unwrap();
+        assert!(send_request(&mut client2, &large, fds).is_err());
+    }
+
+    #[test]
+    fn request_timeout() {
+        let (_client, mut server) = UnixStream::pair().unwrap();
+        let start = std::time::Instant::now();
+        assert!(recv_request(&mut server).is_err());
+        assert!(start.elapsed() < REQUEST_TIMEOUT * 5);
+    }
+
+    #[test]
+    fn canonical_config_path_resolves() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        fs::write(&path, "").unwrap();
+        let link = tmp_dir.path().join("link.toml");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        let canonical = fs::canonicalize(&path).unwrap();
+
+        assert_eq!(canonical_config_path(&link), canonical);
+        assert_eq!(
+            canonical_config_path(&tmp_dir.path().join(".").join("config.toml")),
+            canonical
+        );
+        let missing = tmp_dir.path().join("missing.toml");
+        assert_eq!(canonical_config_path(&missing), missing);
+    }
+
+    #[test]
+    fn config_watch_changed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        let dropin_dir = tmp_dir.path().join("config.d");
+        fs::write(&path, "").unwrap();
+        fs::create_dir(&dropin_dir).unwrap();
+        let watch = ConfigWatch::new(&[path.clone(), dropin_dir.clone()]).unwrap();
+        assert!(!watch.changed());
+
+        // Write
+        fs::write(&path, "a").unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+
+        // Unrelated file in the same directory
+        let tmp_path = tmp_dir.path().join("config.toml.tmp");
+        fs::write(&tmp_path, "b").unwrap();
+        assert!(!watch.changed());
+
+        // Rename over config file, like editors do
+        fs::rename(&tmp_path, &path).unwrap();
+        assert!(watch.changed());
+
+        // Drop-in create
+        fs::write(dropin_dir.join("notes.txt"), "").unwrap();
+        assert!(!watch.changed());
+        fs::write(dropin_dir.join("10-extra.toml"), "").unwrap();
+        assert!(watch.changed());
+    }
+
+    #[test]
+    fn reload_invalid_keeps_logging() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        let log_path = tmp_dir.path().join("rsop.log");
+        fs::write(
+            &path,
+            format!(
+                r#"
+[filetype.orphan]
+mimes = ["text/plain"]
+
+[default_handler_preview]
+command = "file %i"
+
+[default_handler_open]
+command = "xdg-open %i"
+
+[log]
+level = "trace"
+destination = "file"
+file = {log_path:?}
+"#
+            ),
+        )
+        .unwrap();
+        let level = log::max_level();
+
+        let err = reload(Some(&path)).unwrap_err();
+
+        assert!(format!("{err:#}").contains("not bound"));
+        assert_eq!(log::max_level(), level);
+        assert!(!log_path.exists());
+    }
+}
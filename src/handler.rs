@@ -1,23 +1,32 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     env,
-    fs::File,
-    io::{self, Read, Write, copy, stdin},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, IsTerminal as _, Read, Write, copy, stdin},
     iter,
+    num::NonZeroUsize,
     os::unix::{
+        ffi::OsStrExt as _,
         fs::FileTypeExt as _,
         io::{AsRawFd as _, FromRawFd as _},
+        process::CommandExt as _,
     },
+    panic,
     path::{Path, PathBuf},
-    process::{Child, Command, Stdio},
+    process::{Child, Command, ExitStatus, Stdio},
     rc::Rc,
+    thread,
+    time::{Instant, SystemTime},
 };
 
 use anyhow::Context as _;
 
 use crate::{
     RsopMode, config,
-    config::{FileFilter, FileHandler, SchemeHandler},
+    config::{FileFilter, FileHandler, ProcessOptions, Requirement, SchemeHandler},
+    logger, stderr_log,
 };
 
 #[derive(Debug)]
@@ -36,21 +45,25 @@ enum PipeOrTmpFile<T> {
 impl FileProcessor {
     /// Return true if command string contains a given % prefixed pattern
     fn has_pattern(&self, pattern: char) -> bool {
-        let re_str = format!("[^%]%{pattern}");
-        #[expect(clippy::unwrap_used)]
-        let re = regex::Regex::new(&re_str).unwrap();
         let command = match self {
             FileProcessor::Filter(f) => &f.command,
             FileProcessor::Handler(h) => &h.command,
         };
-        re.is_match(command)
+        HandlerMapping::count_pattern(command, pattern) > 0
     }
 }
 
+/// Processor, and name of the filetype it was bound to
+#[derive(Debug)]
+struct BoundProcessor {
+    filetype: String,
+    processor: Rc<FileProcessor>,
+}
+
 #[derive(Debug)]
 struct FileHandlers {
-    extensions: HashMap<String, Rc<FileProcessor>>,
-    mimes: HashMap<String, Rc<FileProcessor>>,
+    extensions: HashMap<String, BoundProcessor>,
+    mimes: HashMap<String, BoundProcessor>,
     default: FileHandler,
 }
 
@@ -63,31 +76,140 @@ impl FileHandlers {
         }
     }
 
-    pub(crate) fn add(&mut self, processor: &Rc<FileProcessor>, filetype: &config::Filetype) {
+    pub(crate) fn add(
+        &mut self,
+        name: &str,
+        processor: &Rc<FileProcessor>,
+        filetype: &config::Filetype,
+    ) {
+        let bind = || BoundProcessor {
+            filetype: name.to_owned(),
+            processor: Rc::clone(processor),
+        };
         for extension in &filetype.extensions {
-            self.extensions
-                .insert(extension.clone(), Rc::clone(processor));
+            self.extensions.insert(extension.clone(), bind());
         }
         for mime in &filetype.mimes {
-            self.mimes.insert(mime.clone(), Rc::clone(processor));
+            self.mimes.insert(mime.clone(), bind());
+        }
+    }
+
+    /// Find usable processor for the first matching extension
+    fn by_extension<'a>(
+        &self,
+        extensions: &'a [String],
+        caps: Capabilities,
+    ) -> Option<(&BoundProcessor, &'a str)> {
+        extensions.iter().find_map(|e| {
+            self.extensions
+                .get(e)
+                .filter(|p| caps.satisfy(p))
+                .map(|p| (p, e.as_str()))
+        })
+    }
+
+    /// Find usable processor for a MIME type or its parent types, and return the MIME that matched
+    fn by_mime(&self, mime: &str, caps: Capabilities) -> Option<(&BoundProcessor, String)> {
+        HandlerMapping::split_mime(mime)
+            .into_iter()
+            .find_map(|sub_mime| {
+                log::trace!("Trying MIME {sub_mime:?}");
+                self.mimes
+                    .get(&sub_mime)
+                    .filter(|p| caps.satisfy(p))
+                    .map(|p| (p, sub_mime))
+            })
+    }
+}
+
+/// What the current session can display, to select handlers based on their requirements
+#[derive(Clone, Copy, Debug)]
+struct Capabilities {
+    gui: bool,
+    tty: bool,
+}
+
+impl Capabilities {
+    /// Capabilities that satisfy any requirement
+    const ALL: Capabilities = Capabilities {
+        gui: true,
+        tty: true,
+    };
+
+    fn detect() -> Capabilities {
+        let gui = ["DISPLAY", "WAYLAND_DISPLAY"]
+            .into_iter()
+            .any(|v| env::var_os(v).is_some_and(|v| !v.is_empty()));
+        let tty = io::stdout().is_terminal();
+        log::trace!("gui={gui}, tty={tty}");
+        Capabilities { gui, tty }
+    }
+
+    /// Return true if the requirement of a processor is met
+    fn satisfy(self, bound: &BoundProcessor) -> bool {
+        let FileProcessor::Handler(handler) = bound.processor.as_ref() else {
+            return true;
+        };
+        let Some(requirement) = handler.requires else {
+            return true;
+        };
+        let met = match requirement {
+            Requirement::Gui => self.gui,
+            Requirement::Tty => self.tty,
+        };
+        if !met {
+            log::debug!(
+                "Skipping handler for filetype {:?}, it requires {requirement}",
+                bound.filetype
+            );
         }
+        met
     }
 }
 
 #[derive(Debug)]
 struct SchemeHandlers {
     schemes: HashMap<String, SchemeHandler>,
+    default: Option<SchemeHandler>,
 }
 
 impl SchemeHandlers {
-    pub(crate) fn new() -> SchemeHandlers {
-        SchemeHandlers {
-            schemes: HashMap::new(),
+    /// Create with an optional default handler, `desc` is used to locate it in error messages
+    pub(crate) fn new(
+        default: Option<&SchemeHandler>,
+        desc: &str,
+    ) -> anyhow::Result<SchemeHandlers> {
+        if let Some(handler) = default {
+            Self::validate(handler, desc)?;
         }
+        Ok(SchemeHandlers {
+            schemes: HashMap::new(),
+            default: default.cloned(),
+        })
+    }
+
+    /// Get handler for a scheme, or the default one
+    fn get(&self, scheme: &str) -> Option<&SchemeHandler> {
+        self.schemes.get(scheme).or(self.default.as_ref())
     }
 
-    pub(crate) fn add(&mut self, handler: &SchemeHandler, scheme: &str) {
+    pub(crate) fn add(
+        &mut self,
+        handler: &SchemeHandler,
+        scheme: &str,
+        desc: &str,
+    ) -> anyhow::Result<()> {
+        Self::validate(handler, desc)?;
         self.schemes.insert(scheme.to_owned(), handler.clone());
+        Ok(())
+    }
+
+    fn validate(handler: &SchemeHandler, desc: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            handler.rules.iter().all(|r| r.handler.rules.is_empty()),
+            "{desc} has rules with rules, they can not be nested"
+        );
+        Ok(())
     }
 }
 
@@ -97,20 +219,308 @@ pub(crate) struct HandlerMapping {
     open: FileHandlers,
     edit: FileHandlers,
     scheme: SchemeHandlers,
+    /// Command prefix to run terminal handlers with, when not attached to a terminal
+    terminal_command: Option<String>,
 }
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum HandlerError {
     #[error("Failed to run handler command {:?}: {err}", .cmd.connect(" "))]
     Start { err: io::Error, cmd: Vec<String> },
+    #[error(
+        "Handler command {:?} failed with {status}{}",
+        .cmd.connect(" "),
+        .stderr.iter().fold(String::new(), |acc, l| acc + "\n  " + l)
+    )]
+    Exit {
+        status: ExitStatus,
+        cmd: Vec<String>,
+        /// Last lines of captured stderr
+        stderr: Vec<String>,
+    },
     #[error("Failed to read input file {path:?}: {err}")]
     Input { err: io::Error, path: PathBuf },
+    #[error("No handler for scheme {0:?}")]
+    NoSchemeHandler(String),
+    #[error("File URL {0:?} is not on this host, set up a handler_scheme.file section to open it")]
+    RemoteFile(String),
     #[error(transparent)]
     Io(#[from] io::Error),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+/// Output format for identify mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum IdentifyFormat {
+    /// Raw MIME type
+    Mime,
+    /// JSON object with MIME and handler details
+    Json,
+    /// Name of the matching filetype
+    Filetype,
+}
+
+/// How a handler was selected
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum MatchSource {
+    Extension,
+    Mime,
+    Default,
+}
+
+/// Handler that would be used for a given mode
+#[derive(Debug, serde::Serialize)]
+struct HandlerMatch {
+    filetype: Option<String>,
+    filter: bool,
+    command: String,
+    source: MatchSource,
+}
+
+/// Result of file identification
+#[derive(Debug, serde::Serialize)]
+struct Identification<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a Path>,
+    mime: Option<&'a str>,
+    filetype: Option<&'a str>,
+    mime_hierarchy: Vec<String>,
+    extensions: Vec<String>,
+    preview: HandlerMatch,
+    open: HandlerMatch,
+    edit: HandlerMatch,
+}
+
+/// Part of a command template
+#[derive(Debug, Eq, PartialEq)]
+enum TemplatePart {
+    /// Literal text, with escaped patterns resolved
+    Text(String),
+    /// % prefixed pattern, or internal letter of a `%{name}` pattern
+    Pattern(char),
+    /// `%{query:name}` pattern, for a URL query parameter
+    QueryParam(String),
+}
+
+impl TemplatePart {
+    /// Whether this is a URL component pattern, which is substituted with an empty argument when the component is
+    /// missing, rather than dropped, so it does not shift the following arguments
+    fn is_url_component(&self) -> bool {
+        match self {
+            TemplatePart::Pattern(p) => matches!(p, 'u' | 'h' | 'p' | 'P' | 'q' | 'F'),
+            TemplatePart::QueryParam(_) => true,
+            TemplatePart::Text(_) => false,
+        }
+    }
+
+    /// Pattern as written in a template, to keep it as is when its value is not available
+    fn pattern_text(&self) -> String {
+        match self {
+            TemplatePart::Pattern(p) => SUBST_NAMED_PATTERNS
+                .iter()
+                .find(|(_, np)| np == p)
+                .map_or_else(|| format!("%{p}"), |(name, _)| format!("%{{{name}}}")),
+            TemplatePart::QueryParam(name) => format!("%{{{QUERY_PARAM_PREFIX}{name}}}"),
+            TemplatePart::Text(text) => text.clone(),
+        }
+    }
+}
+
+/// How a processor was selected for an input
+#[derive(Clone, Copy, Debug, Default)]
+struct MatchContext<'a> {
+    /// MIME type, or sub MIME type that matched
+    mime: Option<&'a str>,
+    /// Name of the matching filetype
+    filetype: Option<&'a str>,
+    /// Extension that matched, or last extension of the input file
+    extension: Option<&'a str>,
+}
+
+/// Values of % prefixed patterns for a command invocation
+struct Substitutions<'a> {
+    /// Path passed to the command, may be a temporary file or stdin argument
+    path: &'a Path,
+    /// Input file, if not reading from a pipe
+    input: Option<&'a Path>,
+    /// URL, for scheme handlers
+    url: Option<&'a url::Url>,
+    matched: MatchContext<'a>,
+    term_size: (u16, u16),
+    tmp_file: Option<&'a tempfile::NamedTempFile>,
+    tmp_dir: Option<&'a tempfile::TempDir>,
+}
+
+impl Substitutions<'_> {
+    /// Value of a pattern, or `None` if it is not available
+    fn value(&self, pattern: char) -> anyhow::Result<Option<String>> {
+        let path_str = |p: &Path| {
+            p.to_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| anyhow::anyhow!("Invalid path {p:?}"))
+        };
+        let os_str = |s: &OsStr| {
+            s.to_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| anyhow::anyhow!("Invalid path component {s:?}"))
+        };
+        Ok(match pattern {
+            'c' => Some(self.term_size.0.to_string()),
+            'l' => Some(self.term_size.1.to_string()),
+            'i' => Some(path_str(self.path)?),
+            'm' => self.matched.mime.map(ToOwned::to_owned),
+            'n' => Some(self.matched.filetype.unwrap_or_default().to_owned()),
+            'e' => Some(self.matched.extension.unwrap_or_default().to_owned()),
+            't' => self.tmp_file.map(|f| path_str(f.path())).transpose()?,
+            'u' | 'h' | 'p' | 'P' | 'q' | 'F' => self.url.map(|url| match pattern {
+                'u' => percent_decode(url.username()),
+                'h' => url.host_str().unwrap_or_default().to_owned(),
+                'p' => url
+                    .port_or_known_default()
+                    .map(|p| p.to_string())
+                    .unwrap_or_default(),
+                'P' => percent_decode(url.path()),
+                'q' => url.query().unwrap_or_default().to_owned(),
+                _ => percent_decode(url.fragment().unwrap_or_default()),
+            }),
+            'T' => self.tmp_dir.map(|d| path_str(d.path())).transpose()?,
+            'f' | 'd' | 's' | 'z' | 'M' => {
+                // Values that only make sense for an input file are empty when reading from a pipe
+                let Some(input) = self.input else {
+                    return Ok(Some(String::new()));
+                };
+                Some(match pattern {
+                    'f' => input
+                        .file_name()
+                        .map(os_str)
+                        .transpose()?
+                        .unwrap_or_default(),
+                    'd' => match input.parent() {
+                        Some(parent) if !parent.as_os_str().is_empty() => path_str(parent)?,
+                        _ => ".".to_owned(),
+                    },
+                    's' => self.stem(input)?,
+                    'z' => Self::metadata(input)?.len().to_string(),
+                    _ => Self::metadata(input)?
+                        .modified()
+                        .context("Unable to get modification time")?
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                        .to_string(),
+                })
+            }
+            _ => None,
+        })
+    }
+
+    /// Value of a URL query parameter, empty if it is missing, or `None` if there is no URL
+    fn query_param(&self, name: &str) -> Option<String> {
+        self.url.map(|url| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+                .unwrap_or_default()
+        })
+    }
+
+    /// File name without the matched extension, or without its last extension
+    fn stem(&self, input: &Path) -> anyhow::Result<String> {
+        let filename = input
+            .file_name()
+            .and_then(|f| f.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Unable to get file name from path {input:?}"))?;
+        let ext_part_count = self
+            .matched
+            .extension
+            .filter(|e| !e.is_empty())
+            .map_or(1, |e| e.split('.').count());
+        let parts: Vec<_> = filename.split('.').collect();
+        // Hidden files have no stem to strip, ie. '.bashrc'
+        let stem_part_count = parts.len().saturating_sub(ext_part_count).max(1);
+        if parts.first().is_some_and(|p| p.is_empty()) && (stem_part_count == 1) {
+            return Ok(filename.to_owned());
+        }
+        Ok(parts[..stem_part_count].join("."))
+    }
+
+    /// Set environment variables describing the invocation context
+    ///
+    /// Variables without a value are removed, so that they are not inherited from a parent rsop instance.
+    fn set_env(
+        &self,
+        command: &mut Command,
+        mode: &RsopMode,
+        filter_depth: usize,
+    ) -> anyhow::Result<()> {
+        let path = if self.input.is_some() {
+            self.value('i')?
+        } else {
+            None
+        };
+        let vars = [
+            ("RSOP_HANDLER_MODE", Some(mode.to_string())),
+            ("RSOP_MIME", self.matched.mime.map(ToOwned::to_owned)),
+            (
+                "RSOP_FILETYPE",
+                self.matched.filetype.map(ToOwned::to_owned),
+            ),
+            ("RSOP_PATH", path),
+            ("RSOP_COLUMNS", Some(self.term_size.0.to_string())),
+            ("RSOP_LINES", Some(self.term_size.1.to_string())),
+            ("RSOP_FILTER_DEPTH", Some(filter_depth.to_string())),
+        ];
+        for (key, value) in vars {
+            if let Some(value) = value {
+                command.env(key, value);
+            } else {
+                command.env_remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(input: &Path) -> anyhow::Result<fs::Metadata> {
+        input
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {input:?}"))
+    }
+}
+
+/// Letters of % prefixed patterns substituted in commands
+const SUBST_PATTERNS: [char; 6] = ['c', 'i', 'l', 'm', 't', 'T'];
+
+/// Names of `%{name}` patterns substituted in commands, and the letters they are handled as internally
+///
+/// These use braces so they can not collide with commands using the same letters literally, ie. `printf '%s'`.
+const SUBST_NAMED_PATTERNS: [(&str, char); 13] = [
+    ("dir", 'd'),
+    ("ext", 'e'),
+    ("filename", 'f'),
+    ("filetype", 'n'),
+    ("fragment", 'F'),
+    ("host", 'h'),
+    ("mtime", 'M'),
+    ("path", 'P'),
+    ("port", 'p'),
+    ("query", 'q'),
+    ("size", 'z'),
+    ("stem", 's'),
+    ("user", 'u'),
+];
+
+/// Prefix of the name of `%{query:name}` patterns, for URL query parameters
+const QUERY_PARAM_PREFIX: &str = "query:";
+
+/// Decode percent encoded URL component
+fn percent_decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
 /// How many bytes to read from pipe to guess MIME type, use a full memory page
 const PIPE_INITIAL_READ_LENGTH: usize = 4096;
 
@@ -130,35 +540,62 @@ impl HandlerMapping {
                     || handler_edit.is_some()
                     || handler_preview.is_some()
                     || filter.is_some(),
-                "Filetype {name} is not bound to any handler or filter"
+                "{} is not bound to any handler or filter",
+                cfg.describe(&format!("filetype.{name}"))
             );
             if let Some(handler_open) = handler_open {
-                Self::validate_handler(&handler_open)?;
-                handlers_open.add(&Rc::new(FileProcessor::Handler(handler_open)), filetype);
+                Self::validate_handler(
+                    &handler_open,
+                    &cfg.describe(&format!("handler_open.{name}")),
+                )?;
+                handlers_open.add(
+                    name,
+                    &Rc::new(FileProcessor::Handler(handler_open)),
+                    filetype,
+                );
             }
             if let Some(handler_edit) = handler_edit {
-                Self::validate_handler(&handler_edit)?;
-                handlers_edit.add(&Rc::new(FileProcessor::Handler(handler_edit)), filetype);
+                Self::validate_handler(
+                    &handler_edit,
+                    &cfg.describe(&format!("handler_edit.{name}")),
+                )?;
+                handlers_edit.add(
+                    name,
+                    &Rc::new(FileProcessor::Handler(handler_edit)),
+                    filetype,
+                );
             }
             if let Some(handler_preview) = handler_preview {
-                Self::validate_handler(&handler_preview)?;
-                handlers_preview.add(&Rc::new(FileProcessor::Handler(handler_preview)), filetype);
+                Self::validate_handler(
+                    &handler_preview,
+                    &cfg.describe(&format!("handler_preview.{name}")),
+                )?;
+                handlers_preview.add(
+                    name,
+                    &Rc::new(FileProcessor::Handler(handler_preview)),
+                    filetype,
+                );
             }
             if let Some(filter) = filter {
                 anyhow::ensure!(
                     filter.no_pipe || (Self::count_pattern(&filter.command, 'i') <= 1),
-                    "Filter {filter:?} can not have both 'no_pipe = false' and multiple %i in command"
+                    "{} can not have both 'no_pipe = false' and multiple %i in command",
+                    cfg.describe(&format!("filter.{name}"))
                 );
                 let proc_filter = Rc::new(FileProcessor::Filter(filter));
-                handlers_open.add(&Rc::clone(&proc_filter), filetype);
-                // handlers_edit.add(&Rc::clone(&proc_filter), filetype);
-                handlers_preview.add(&Rc::clone(&proc_filter), filetype);
+                handlers_open.add(name, &proc_filter, filetype);
+                // handlers_edit.add(name, &proc_filter, filetype);
+                handlers_preview.add(name, &proc_filter, filetype);
             }
         }
 
-        let mut handlers_scheme = SchemeHandlers::new();
+        let mut handlers_scheme = SchemeHandlers::new(
+            cfg.default_handler_scheme.as_ref(),
+            &cfg.describe("default_handler_scheme"),
+        )?;
         for (schemes, handler) in &cfg.handler_scheme {
-            handlers_scheme.add(handler, schemes);
+            let desc = cfg.describe(&format!("handler_scheme.{schemes}"));
+            handlers_scheme.add(handler, schemes, &desc)?;
         }
 
         Ok(HandlerMapping {
@@ -166,14 +603,16 @@ impl HandlerMapping {
             open: handlers_open,
             edit: handlers_edit,
             scheme: handlers_scheme,
+            terminal_command: cfg.terminal_command.clone(),
         })
     }
 
-    fn validate_handler(handler: &FileHandler) -> anyhow::Result<()> {
+    /// Check handler parameters are consistent, `desc` is used to locate it in error messages
+    fn validate_handler(handler: &FileHandler, desc: &str) -> anyhow::Result<()> {
         #[cfg(not(target_os = "linux"))]
         anyhow::ensure!(
             !handler.no_pipe || handler.wait,
-            "Handler {handler:?} can not have both 'no_pipe = true' and 'wait = false'"
+            "{desc} can not have both 'no_pipe = true' and 'wait = false'"
         );
         #[cfg(target_os = "linux")]
         anyhow::ensure!(
@@ -181,50 +620,305 @@ impl HandlerMapping {
                 || handler.wait
                 || (Self::count_pattern(&handler.command, 't') == 0
                     && Self::count_pattern(&handler.command, 'T') == 0),
-            "Handler {handler:?} can not have 'no_pipe = true' and 'wait = false' with %t or %T patterns"
+            "{desc} can not have 'no_pipe = true' and 'wait = false' with %t or %T patterns"
         );
         anyhow::ensure!(
             handler.no_pipe || (Self::count_pattern(&handler.command, 'i') <= 1),
-            "Handler {handler:?} can not have both 'no_pipe = false' and multiple %i in command"
+            "{desc} can not have both 'no_pipe = false' and multiple %i in command"
         );
         Ok(())
     }
 
     /// Count number of a given % prefixed pattern in command string
     fn count_pattern(command: &str, pattern: char) -> usize {
-        let re_str = format!("[^%]%{pattern}");
-        #[expect(clippy::unwrap_used)]
-        let re = regex::Regex::new(&re_str).unwrap();
-        re.find_iter(command).count()
+        Self::parse_template(command)
+            .iter()
+            .filter(|p| **p == TemplatePart::Pattern(pattern))
+            .count()
+    }
+
+    /// Split command template into literal text and % prefixed patterns
+    fn parse_template(s: &str) -> Vec<TemplatePart> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            match chars.peek().copied() {
+                Some('%')
+                    if chars
+                        .clone()
+                        .nth(1)
+                        .is_some_and(|n| (n == '{') || SUBST_PATTERNS.contains(&n)) =>
+                {
+                    // Escaped pattern
+                    chars.next();
+                    text.push('%');
+                }
+                Some('{') => {
+                    let name: String = chars
+                        .clone()
+                        .skip(1)
+                        .take_while(|b| !matches!(b, '{' | '}' | '%') && !b.is_whitespace())
+                        .collect();
+                    let len = name.chars().count();
+                    let part = if let Some(param) = name.strip_prefix(QUERY_PARAM_PREFIX) {
+                        (!param.is_empty()).then(|| TemplatePart::QueryParam(param.to_owned()))
+                    } else {
+                        SUBST_NAMED_PATTERNS
+                            .iter()
+                            .find(|(n, _)| *n == name)
+                            .map(|(_, p)| TemplatePart::Pattern(*p))
+                    }
+                    .filter(|_| chars.clone().nth(len + 1) == Some('}'));
+                    if let Some(part) = part {
+                        chars.nth(len + 1);
+                        if !text.is_empty() {
+                            parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                        }
+                        parts.push(part);
+                    } else {
+                        text.push(c);
+                    }
+                }
+                Some(n) if SUBST_PATTERNS.contains(&n) => {
+                    chars.next();
+                    if !text.is_empty() {
+                        parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(TemplatePart::Pattern(n));
+                }
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(TemplatePart::Text(text));
+        }
+        parts
     }
 
     pub(crate) fn handle_path(&self, mode: &RsopMode, path: &Path) -> Result<(), HandlerError> {
-        if let (RsopMode::XdgOpen, Ok(url)) = (
-            &mode,
-            url::Url::parse(
-                path.to_str()
-                    .ok_or_else(|| anyhow::anyhow!("Unable to decode path {path:?}"))?,
-            ),
+        if *mode != RsopMode::XdgOpen {
+            return self.dispatch_path(path, mode);
+        }
+
+        let path = match url::Url::parse(
+            path.to_str()
+                .ok_or_else(|| anyhow::anyhow!("Unable to decode path {path:?}"))?,
         ) {
-            if url.scheme() == "file" {
-                let url_path = &url[url::Position::BeforeUsername..];
-                let parsed_path = PathBuf::from(url_path);
+            Ok(url) if url.scheme() == "file" => {
+                let Some(parsed_path) = Self::local_file_path(&url) else {
+                    log::debug!("File URL {url} is not on this host");
+                    return self.dispatch_url(&url, mode);
+                };
                 log::trace!("url={url}, parsed_path={parsed_path:?}");
-                self.dispatch_path(&parsed_path, mode)
-            } else {
-                self.dispatch_url(&url)
+                parsed_path
             }
-        } else {
-            self.dispatch_path(path, mode)
-        }
+            Ok(url) => return self.dispatch_url(&url, mode),
+            Err(_) => path.to_owned(),
+        };
+        // Like xdg-open, fail early instead of running a handler on a missing file
+        fs::metadata(&path).map_err(|e| HandlerError::Input {
+            err: e,
+            path: path.clone(),
+        })?;
+        self.dispatch_path(&path, mode)
     }
 
     pub(crate) fn handle_pipe(&self, mode: &RsopMode) -> Result<(), HandlerError> {
         let stdin = Self::stdin_reader();
-        self.dispatch_pipe(stdin, mode)
+        self.dispatch_pipe(stdin, mode, 0)
+    }
+
+    pub(crate) fn identify_path(
+        &self,
+        path: &Path,
+        format: IdentifyFormat,
+    ) -> Result<(), HandlerError> {
+        let mime = Self::path_mime(path).map_err(|e| HandlerError::Input {
+            err: e,
+            path: path.to_owned(),
+        })?;
+        println!("{}", self.identification_line(Some(path), mime, format)?);
+        Ok(())
+    }
+
+    /// Identify several paths, probing MIME types in parallel, and output one line per path in input order
+    pub(crate) fn identify_paths(
+        &self,
+        paths: &[PathBuf],
+        format: IdentifyFormat,
+    ) -> Result<(), HandlerError> {
+        let mimes = Self::paths_mimes(paths);
+        let mut stdout = io::stdout().lock();
+        let mut failures: usize = 0;
+        for (path, mime) in paths.iter().zip(mimes) {
+            let line = mime
+                .map_err(|e| HandlerError::Input {
+                    err: e,
+                    path: path.to_owned(),
+                })
+                .and_then(|mime| Ok(self.identification_line(Some(path), mime, format)?));
+            match line {
+                Ok(line) if format == IdentifyFormat::Json => writeln!(stdout, "{line}")?,
+                Ok(line) => writeln!(stdout, "{}\t{line}", path.display())?,
+                Err(err) => {
+                    log::error!("{err}");
+                    failures += 1;
+                }
+            }
+        }
+        if failures > 0 {
+            return Err(HandlerError::Other(anyhow::anyhow!(
+                "Failed to identify {failures}/{} paths",
+                paths.len()
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn identify_pipe(&self, format: IdentifyFormat) -> Result<(), HandlerError> {
+        let mut stdin = Self::stdin_reader();
+        let mut buffer: Vec<u8> = vec![0; PIPE_INITIAL_READ_LENGTH];
+        let header_len = stdin.read(&mut buffer)?;
+        let mime = tree_magic_mini::from_u8(&buffer[0..header_len]);
+        log::debug!("MIME: {mime:?}");
+        println!("{}", self.identification_line(None, Some(mime), format)?);
+        Ok(())
+    }
+
+    /// Build identify mode output for a path (or piped data if `None`)
+    fn identification_line(
+        &self,
+        path: Option<&Path>,
+        mime: Option<&str>,
+        format: IdentifyFormat,
+    ) -> anyhow::Result<String> {
+        let extensions = match path {
+            Some(path) => Self::path_extensions(path)?,
+            None => vec![],
+        };
+        match format {
+            IdentifyFormat::Mime => mime
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| anyhow::anyhow!("Unable to get MIME type for {path:?}")),
+            IdentifyFormat::Json => serde_json::to_string(&self.identify(path, extensions, mime))
+                .context("Failed to serialize to JSON"),
+            IdentifyFormat::Filetype => self
+                .filetype(&extensions, mime)
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| anyhow::anyhow!("No filetype matches {path:?} (MIME {mime:?})")),
+        }
+    }
+
+    /// Probe MIME types of several paths, using one thread per CPU
+    fn paths_mimes(paths: &[PathBuf]) -> Vec<Result<Option<&'static str>, io::Error>> {
+        let thread_count = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let chunk_size = paths.len().div_ceil(thread_count).max(1);
+        thread::scope(|scope| {
+            let workers: Vec<_> = paths
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope
+                        .spawn(move || chunk.iter().map(|p| Self::path_mime(p)).collect::<Vec<_>>())
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    }
+
+    fn identify<'a>(
+        &'a self,
+        path: Option<&'a Path>,
+        extensions: Vec<String>,
+        mime: Option<&'a str>,
+    ) -> Identification<'a> {
+        let caps = Capabilities::detect();
+        Identification {
+            path,
+            mime,
+            filetype: self.filetype(&extensions, mime),
+            mime_hierarchy: mime.map(Self::split_mime).unwrap_or_default(),
+            preview: self.resolve(&RsopMode::Preview, &extensions, mime, caps),
+            open: self.resolve(&RsopMode::Open, &extensions, mime, caps),
+            edit: self.resolve(&RsopMode::Edit, &extensions, mime, caps),
+            extensions,
+        }
+    }
+
+    /// Find the filetype matching extension first, then MIME type, regardless of mode
+    fn filetype(&self, extensions: &[String], mime: Option<&str>) -> Option<&str> {
+        let handlers = || [&self.open, &self.edit, &self.preview].into_iter();
+        handlers()
+            .find_map(|h| h.by_extension(extensions, Capabilities::ALL))
+            .map(|(p, _)| p)
+            .or_else(|| {
+                let mime = mime?;
+                handlers()
+                    .find_map(|h| h.by_mime(mime, Capabilities::ALL))
+                    .map(|(p, _)| p)
+            })
+            .map(|p| p.filetype.as_str())
+    }
+
+    /// Find which handler would be used in a given mode, with the same logic as `dispatch_path`
+    fn resolve(
+        &self,
+        mode: &RsopMode,
+        extensions: &[String],
+        mime: Option<&str>,
+        caps: Capabilities,
+    ) -> HandlerMatch {
+        let (mode_handlers, next_handlers) = self.mode_handlers(mode);
+        let handlers = || iter::once(mode_handlers).chain(next_handlers);
+        let found = handlers()
+            .find_map(|h| h.by_extension(extensions, caps))
+            .map(|(p, _)| (p, MatchSource::Extension))
+            .or_else(|| {
+                let mime = mime?;
+                handlers()
+                    .find_map(|h| h.by_mime(mime, caps))
+                    .map(|(p, _)| (p, MatchSource::Mime))
+            });
+        match found {
+            Some((bound, source)) => {
+                let (filter, command) = match bound.processor.as_ref() {
+                    FileProcessor::Filter(f) => (true, f.command.clone()),
+                    FileProcessor::Handler(h) => (false, h.command.clone()),
+                };
+                HandlerMatch {
+                    filetype: Some(bound.filetype.clone()),
+                    filter,
+                    command,
+                    source,
+                }
+            }
+            None => HandlerMatch {
+                filetype: None,
+                filter: false,
+                command: mode_handlers.default.command.clone(),
+                source: MatchSource::Default,
+            },
+        }
+    }
+
+    /// Get handlers for a mode, and handlers to fall back to
+    #[expect(clippy::wildcard_in_or_patterns)]
+    fn mode_handlers(&self, mode: &RsopMode) -> (&FileHandlers, Option<&FileHandlers>) {
+        match mode {
+            RsopMode::Preview => (&self.preview, None),
+            RsopMode::Edit => (&self.edit, Some(&self.open)),
+            RsopMode::Open | _ => (&self.open, Some(&self.edit)),
+        }
     }
 
-    fn path_mime(path: &Path) -> Result<Option<&str>, io::Error> {
+    fn path_mime(path: &Path) -> Result<Option<&'static str>, io::Error> {
         // Rather than read socket/pipe, mimic 'file -ib xxx' behavior and return 'inode/yyy' strings
         let metadata = path.metadata()?;
         let file_type = metadata.file_type();
@@ -243,32 +937,37 @@ impl HandlerMapping {
         Ok(mime)
     }
 
-    #[expect(clippy::wildcard_in_or_patterns)]
     fn dispatch_path(&self, path: &Path, mode: &RsopMode) -> Result<(), HandlerError> {
         // Handler candidates, with fallbacks
-        let (mode_handlers, next_handlers) = match mode {
-            RsopMode::Preview => (&self.preview, None),
-            RsopMode::Edit => (&self.edit, Some(&self.open)),
-            RsopMode::Open | _ => (&self.open, Some(&self.edit)),
-        };
+        let (mode_handlers, next_handlers) = self.mode_handlers(mode);
 
         // Try by extension first
-        if *mode != RsopMode::Identify {
-            for handlers in iter::once(mode_handlers).chain(next_handlers) {
-                for extension in Self::path_extensions(path)? {
-                    if let Some(handler) = handlers.extensions.get(&extension) {
-                        let mime = if handler.has_pattern('m') {
-                            // Probe MIME type even if we already found a handler, to substitute in command
-                            Self::path_mime(path).map_err(|e| HandlerError::Input {
-                                err: e,
-                                path: path.to_owned(),
-                            })?
-                        } else {
-                            None
-                        };
-                        return self.run_path(handler, path, mode, mime);
+        let caps = Capabilities::detect();
+        let extensions = Self::path_extensions(path)?;
+        for handlers in iter::once(mode_handlers).chain(next_handlers) {
+            if let Some((handler, extension)) = handlers.by_extension(&extensions, caps) {
+                // Probe MIME type even if we already found a handler, for the command, its environment and the
+                // activity log
+                let mime = match Self::path_mime(path) {
+                    Ok(mime) => mime,
+                    // The handler may still be able to deal with input we can not read, if it does not need the MIME
+                    Err(err) if !handler.processor.has_pattern('m') => {
+                        log::debug!("Failed to probe MIME type of {path:?}: {err}");
+                        None
                     }
-                }
+                    Err(err) => {
+                        return Err(HandlerError::Input {
+                            err,
+                            path: path.to_owned(),
+                        });
+                    }
+                };
+                let matched = MatchContext {
+                    mime,
+                    filetype: Some(&handler.filetype),
+                    extension: Some(extension),
+                };
+                return self.run_path(&handler.processor, path, mode, matched);
             }
         }
 
@@ -276,23 +975,17 @@ impl HandlerMapping {
             err: e,
             path: path.to_owned(),
         })?;
-        if let RsopMode::Identify = mode {
-            println!(
-                "{}",
-                mime.ok_or_else(|| anyhow::anyhow!("Unable to get MIME type for {path:?}"))?
-            );
-            return Ok(());
-        }
 
         // Match by MIME
-        for handlers in iter::once(mode_handlers).chain(next_handlers) {
-            if let Some(mime) = mime {
-                // Try sub MIME types
-                for sub_mime in Self::split_mime(mime) {
-                    log::trace!("Trying MIME {sub_mime:?}");
-                    if let Some(handler) = handlers.mimes.get(&sub_mime) {
-                        return self.run_path(handler, path, mode, Some(&sub_mime));
-                    }
+        if let Some(mime) = mime {
+            for handlers in iter::once(mode_handlers).chain(next_handlers) {
+                if let Some((handler, sub_mime)) = handlers.by_mime(mime, caps) {
+                    let matched = MatchContext {
+                        mime: Some(&sub_mime),
+                        filetype: Some(&handler.filetype),
+                        extension: extensions.last().map(String::as_str),
+                    };
+                    return self.run_path(&handler.processor, path, mode, matched);
                 }
             }
         }
@@ -302,21 +995,26 @@ impl HandlerMapping {
             &FileProcessor::Handler(mode_handlers.default.clone()),
             path,
             mode,
-            mime,
+            MatchContext {
+                mime,
+                filetype: None,
+                extension: extensions.last().map(String::as_str),
+            },
         )
     }
 
-    #[expect(clippy::wildcard_in_or_patterns)]
-    fn dispatch_pipe<T>(&self, mut pipe: T, mode: &RsopMode) -> Result<(), HandlerError>
+    /// Dispatch piped data, `filter_depth` being the number of filters the data went through
+    fn dispatch_pipe<T>(
+        &self,
+        mut pipe: T,
+        mode: &RsopMode,
+        filter_depth: usize,
+    ) -> Result<(), HandlerError>
     where
         T: Read + Send,
     {
         // Handler candidates
-        let (mode_handlers, next_handlers) = match mode {
-            RsopMode::Preview => (&self.preview, None),
-            RsopMode::Edit => (&self.edit, Some(&self.open)),
-            RsopMode::Open | _ => (&self.open, Some(&self.edit)),
-        };
+        let (mode_handlers, next_handlers) = self.mode_handlers(mode);
 
         // Read header
         log::trace!("Using max header length of {PIPE_INITIAL_READ_LENGTH} bytes");
@@ -326,18 +1024,24 @@ impl HandlerMapping {
 
         let mime = tree_magic_mini::from_u8(header);
         log::debug!("MIME: {mime:?}");
-        if let RsopMode::Identify = mode {
-            println!("{mime}");
-            return Ok(());
-        }
 
+        let caps = Capabilities::detect();
         for handlers in iter::once(mode_handlers).chain(next_handlers) {
             // Try sub MIME types
-            for sub_mime in Self::split_mime(mime) {
-                log::trace!("Trying MIME {sub_mime:?}");
-                if let Some(handler) = handlers.mimes.get(&sub_mime) {
-                    return self.run_pipe(handler, header, pipe, Some(&sub_mime), mode);
-                }
+            if let Some((handler, sub_mime)) = handlers.by_mime(mime, caps) {
+                let matched = MatchContext {
+                    mime: Some(&sub_mime),
+                    filetype: Some(&handler.filetype),
+                    extension: None,
+                };
+                return self.run_pipe(
+                    &handler.processor,
+                    header,
+                    pipe,
+                    matched,
+                    mode,
+                    filter_depth,
+                );
             }
         }
 
@@ -346,107 +1050,321 @@ impl HandlerMapping {
             &FileProcessor::Handler(mode_handlers.default.clone()),
             header,
             pipe,
-            Some(mime),
+            MatchContext {
+                mime: Some(mime),
+                ..MatchContext::default()
+            },
             mode,
+            filter_depth,
         )
     }
 
-    fn dispatch_url(&self, url: &url::Url) -> Result<(), HandlerError> {
+    fn dispatch_url(&self, url: &url::Url, mode: &RsopMode) -> Result<(), HandlerError> {
         let scheme = url.scheme();
-        if let Some(handler) = self.scheme.schemes.get(scheme) {
-            return Self::run_url(handler, url);
+        if let Some(handler) = self.scheme.get(scheme) {
+            let handler = handler.for_url(url);
+            if handler.fetch {
+                return self.fetch_url(handler, url, mode);
+            }
+            return Self::run_url(handler, url, mode);
         }
 
-        Err(HandlerError::Other(anyhow::anyhow!(
-            "No handler for scheme {scheme:?}"
-        )))
+        Err(if scheme == "file" {
+            HandlerError::RemoteFile(url.to_string())
+        } else {
+            HandlerError::NoSchemeHandler(scheme.to_owned())
+        })
     }
 
-    // Substitute % prefixed patterns in string
-    fn substitute(
-        s: &str,
-        path: &Path,
-        mime: Option<&str>,
-        term_size: (u16, u16),
-        tmp_file: Option<&tempfile::NamedTempFile>,
-        tmp_dir: Option<&tempfile::TempDir>,
-    ) -> anyhow::Result<String> {
-        const BASE_SUBST_REGEX: &str = "([^%])(%{})";
-        const BASE_SUBST_UNESCAPE_SRC: &str = "%%";
-        const BASE_SUBST_UNESCAPE_DST: &str = "%";
-
-        let mut r = s.to_owned();
-
-        let mut path_arg = path
-            .to_str()
-            .ok_or_else(|| anyhow::anyhow!("Invalid path {path:?}"))?
-            .to_owned();
-        if !path_arg.is_empty() {
-            path_arg = shlex::try_quote(&path_arg)
-                .with_context(|| format!("Failed to quote string {path_arg:?}"))?
-                .to_string();
+    /// Decode path of a file URL, or get `None` if its host is not empty, `localhost` or this host's name
+    fn local_file_path(url: &url::Url) -> Option<PathBuf> {
+        let is_local = url.host_str().is_none_or(|host| {
+            host.eq_ignore_ascii_case("localhost")
+                || nix::unistd::gethostname()
+                    .is_ok_and(|h| h.to_str().is_some_and(|h| h.eq_ignore_ascii_case(host)))
+        });
+        if !is_local {
+            return None;
         }
+        let path: Vec<u8> = percent_encoding::percent_decode_str(url.path()).collect();
+        Some(PathBuf::from(OsStr::from_bytes(&path)))
+    }
 
-        let mut subst_params: Vec<(String, &str, &str, &str)> = vec![
-            (
-                format!("{}", term_size.0),
-                const_format::str_replace!(BASE_SUBST_REGEX, "{}", "c"),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_SRC, 'c'),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_DST, 'c'),
-            ),
-            (
-                format!("{}", term_size.1),
-                const_format::str_replace!(BASE_SUBST_REGEX, "{}", "l"),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_SRC, 'l'),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_DST, 'l'),
-            ),
-            (
-                path_arg,
-                const_format::str_replace!(BASE_SUBST_REGEX, "{}", "i"),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_SRC, 'i'),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_DST, 'i'),
-            ),
-        ];
-        if let Some(mime) = mime {
-            subst_params.push((
-                mime.to_owned(),
-                const_format::str_replace!(BASE_SUBST_REGEX, "{}", "m"),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_SRC, 'm'),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_DST, 'm'),
-            ));
-        }
-        if let Some(tmp_file) = tmp_file {
-            subst_params.push((
-                tmp_file
-                    .path()
-                    .to_str()
-                    .ok_or_else(|| anyhow::anyhow!("Invalid path {path:?}"))?
-                    .to_owned(),
-                const_format::str_replace!(BASE_SUBST_REGEX, "{}", "t"),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_SRC, 't'),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_DST, 't'),
-            ));
-        }
-        if let Some(tmp_dir) = tmp_dir {
-            subst_params.push((
-                tmp_dir
-                    .path()
-                    .to_str()
-                    .ok_or_else(|| anyhow::anyhow!("Invalid path {path:?}"))?
-                    .to_owned(),
-                const_format::str_replace!(BASE_SUBST_REGEX, "{}", "T"),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_SRC, 'T'),
-                const_format::concatcp!(BASE_SUBST_UNESCAPE_DST, 'T'),
-            ));
-        }
-        for (val, re_str, unescape_src, unescape_dst) in subst_params {
-            #[expect(clippy::unwrap_used)]
-            let re = regex::Regex::new(re_str).unwrap();
-            r = re.replace_all(&r, format!("${{1}}{val}")).to_string();
-            r = r.replace(unescape_src, unescape_dst);
+    /// Expand `~`, `$VAR`, `${VAR}` and `${VAR:-default}` in a command not run by a shell
+    fn expand_env(command: &str) -> anyhow::Result<Cow<'_, str>> {
+        if !command.contains(['~', '$']) {
+            return Ok(Cow::Borrowed(command));
         }
+        let r = Self::expand_vars(command, |name| env::var(name).ok())?;
+        Ok(Cow::Owned(r))
+    }
 
-        Ok(r.trim().to_owned())
+    /// Expand variables in command using a lookup function, like a shell would, except for word splitting
+    ///
+    /// Nothing is expanded in single quotes, and only variables are in double quotes.
+    /// Expanded values are quoted so they end up in a single argument, and % patterns in them are escaped so they are
+    /// not substituted.
+    fn expand_vars<F>(command: &str, lookup: F) -> anyhow::Result<String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut r = String::with_capacity(command.len());
+        let mut chars = command.chars().peekable();
+        let mut quote: Option<char> = None;
+        let mut token_start = true;
+        while let Some(c) = chars.next() {
+            let value =
+                match (c, quote) {
+                    ('\\', None | Some('"')) => {
+                        r.push(c);
+                        r.extend(chars.next());
+                        None
+                    }
+                    ('\'' | '"', None) => {
+                        quote = Some(c);
+                        r.push(c);
+                        None
+                    }
+                    (_, Some(q)) if c == q => {
+                        quote = None;
+                        r.push(c);
+                        None
+                    }
+                    ('~', None)
+                        if token_start
+                            && chars.peek().is_none_or(|n| *n == '/' || n.is_whitespace()) =>
+                    {
+                        Some(lookup("HOME").ok_or_else(|| {
+                            anyhow::anyhow!("HOME is not set, unable to expand ~")
+                        })?)
+                    }
+                    ('$', None | Some('"')) => {
+                        let value = Self::expand_var(&mut chars, &lookup, command)?;
+                        if value.is_none() {
+                            // Not a variable, keep as is
+                            r.push(c);
+                        }
+                        value
+                    }
+                    _ => {
+                        r.push(c);
+                        None
+                    }
+                };
+            if let Some(value) = value {
+                let quoted = if quote.is_some() {
+                    value.chars().fold(String::new(), |mut acc, vc| {
+                        if matches!(vc, '\\' | '"' | '$' | '`') {
+                            acc.push('\\');
+                        }
+                        acc.push(vc);
+                        acc
+                    })
+                } else if value.is_empty() {
+                    value
+                } else {
+                    shlex::try_quote(&value)
+                        .with_context(|| format!("Failed to quote string {value:?}"))?
+                        .into_owned()
+                };
+                r.push_str(&Self::escape_patterns(&quoted));
+            }
+            token_start = quote.is_none() && c.is_whitespace();
+        }
+        Ok(r)
+    }
+
+    /// Expand variable following a '$', or get `None` if there is none and the '$' is literal
+    fn expand_var<F>(
+        chars: &mut iter::Peekable<std::str::Chars<'_>>,
+        lookup: &F,
+        command: &str,
+    ) -> anyhow::Result<Option<String>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Ok(if chars.next_if_eq(&'{').is_some() {
+            let mut inner = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(ic) => inner.push(ic),
+                    None => anyhow::bail!("Unterminated '${{' in command {command:?}"),
+                }
+            }
+            let (name, default) = match inner.split_once(":-") {
+                Some((name, default)) => (name.to_owned(), Some(default)),
+                None => (inner.clone(), None),
+            };
+            anyhow::ensure!(
+                Self::is_var_name(&name),
+                "Invalid variable name {name:?} in command {command:?}"
+            );
+            match (lookup(&name), default) {
+                (Some(v), Some(d)) if v.is_empty() => Some(d.to_owned()),
+                (None, Some(d)) => Some(d.to_owned()),
+                (v, _) => Some(v.unwrap_or_default()),
+            }
+        } else {
+            // Only consume a valid name, so the text following a literal '$' is kept as is
+            chars
+                .next_if(|nc| nc.is_ascii_alphabetic() || (*nc == '_'))
+                .map(|first| {
+                    let mut name = String::from(first);
+                    while let Some(nc) =
+                        chars.next_if(|nc| nc.is_ascii_alphanumeric() || (*nc == '_'))
+                    {
+                        name.push(nc);
+                    }
+                    lookup(&name).unwrap_or_default()
+                })
+        })
+    }
+
+    /// Expand `~` and variables in a path from a handler option, without any quoting or word splitting
+    ///
+    /// % patterns in expanded values are escaped so they are not substituted.
+    fn expand_path_vars<F>(template: &str, lookup: F) -> anyhow::Result<String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut r = String::with_capacity(template.len());
+        let mut chars = template.chars().peekable();
+        if chars.peek() == Some(&'~') && template.chars().nth(1).is_none_or(|c| c == '/') {
+            chars.next();
+            let home = lookup("HOME")
+                .ok_or_else(|| anyhow::anyhow!("HOME is not set, unable to expand ~"))?;
+            r.push_str(&Self::escape_patterns(&home));
+        }
+        while let Some(c) = chars.next() {
+            if c == '$' {
+                if let Some(value) = Self::expand_var(&mut chars, &lookup, template)? {
+                    r.push_str(&Self::escape_patterns(&value));
+                    continue;
+                }
+            }
+            r.push(c);
+        }
+        Ok(r)
+    }
+
+    fn is_var_name(name: &str) -> bool {
+        name.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || (c == '_'))
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || (c == '_'))
+    }
+
+    /// Escape % patterns in string, so they are not substituted
+    fn escape_patterns(s: &str) -> String {
+        let mut r = String::with_capacity(s.len());
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            r.push(c);
+            if (c == '%')
+                && chars
+                    .peek()
+                    .is_some_and(|n| (*n == '{') || SUBST_PATTERNS.contains(n))
+            {
+                r.push('%');
+            }
+        }
+        r
+    }
+
+    /// Substitute % prefixed patterns in string, patterns whose value is not available are kept as is
+    fn substitute(s: &str, subst: &Substitutions) -> anyhow::Result<String> {
+        let mut r = String::with_capacity(s.len());
+        for part in Self::parse_template(s) {
+            match &part {
+                TemplatePart::Text(text) => r.push_str(text),
+                TemplatePart::Pattern(pattern) => {
+                    if let Some(value) = subst.value(*pattern)? {
+                        r.push_str(&value);
+                    } else {
+                        r.push_str(&part.pattern_text());
+                    }
+                }
+                TemplatePart::QueryParam(name) => {
+                    if let Some(value) = subst.query_param(name) {
+                        r.push_str(&value);
+                    } else {
+                        r.push_str(&part.pattern_text());
+                    }
+                }
+            }
+        }
+        Ok(r)
+    }
+
+    /// Build shell script from command template, and positional parameters to pass along
+    ///
+    /// Substituted values are never spliced into the script, patterns are replaced by references to positional
+    /// parameters, quoted according to the context they appear in. Terminal size values are inlined because they are
+    /// numeric, and may be used in arithmetic expressions.
+    fn shell_script(command: &str, subst: &Substitutions) -> anyhow::Result<(String, Vec<String>)> {
+        let mut script = String::with_capacity(command.len());
+        let mut params: Vec<String> = Vec::new();
+        // Patterns of positional parameters, to reuse them for repeated patterns
+        let mut param_patterns: Vec<TemplatePart> = Vec::new();
+        let mut quote: Option<char> = None;
+        for part in Self::parse_template(command) {
+            let value = match &part {
+                TemplatePart::Text(text) => {
+                    let mut chars = text.chars();
+                    while let Some(c) = chars.next() {
+                        script.push(c);
+                        match (c, quote) {
+                            ('\\', None | Some('"')) => script.extend(chars.next()),
+                            ('\'' | '"', None) => quote = Some(c),
+                            (_, Some(q)) if c == q => quote = None,
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                TemplatePart::Pattern(pattern) => match subst.value(*pattern)? {
+                    None => {
+                        script.push_str(&part.pattern_text());
+                        continue;
+                    }
+                    Some(value) if matches!(pattern, 'c' | 'l') => {
+                        script.push_str(&value);
+                        continue;
+                    }
+                    Some(value) => value,
+                },
+                TemplatePart::QueryParam(name) => {
+                    if let Some(value) = subst.query_param(name) {
+                        value
+                    } else {
+                        script.push_str(&part.pattern_text());
+                        continue;
+                    }
+                }
+            };
+            if value.is_empty() && !part.is_url_component() {
+                continue;
+            }
+            let idx = if let Some(idx) = param_patterns.iter().position(|p| *p == part) {
+                idx + 1
+            } else {
+                param_patterns.push(part);
+                params.push(value);
+                params.len()
+            };
+            // Braces are needed from the 10th parameter on, '$10' being '$1' followed by '0'
+            let reference = match quote {
+                None => format!("\"${{{idx}}}\""),
+                Some('"') => format!("${{{idx}}}"),
+                // Close single quotes to reference parameter, and reopen them
+                Some(_) => format!("'\"${{{idx}}}\"'"),
+            };
+            script.push_str(&reference);
+        }
+        Ok((script.trim().to_owned(), params))
     }
 
     // Get terminal size by probing it, reading it from env, or using fallback
@@ -479,20 +1397,25 @@ impl HandlerMapping {
         processor: &FileProcessor,
         path: &Path,
         mode: &RsopMode,
-        mime: Option<&str>,
+        matched: MatchContext,
     ) -> Result<(), HandlerError> {
         let term_size = Self::term_size();
 
         match processor {
             FileProcessor::Handler(handler) => {
-                Self::run_path_handler(handler, path, mime, term_size)
+                let terminal_command = self.terminal_command.as_deref();
+                Self::run_path_handler(handler, path, matched, term_size, mode, terminal_command)
             }
             FileProcessor::Filter(filter) => {
-                let mut filter_child = Self::run_path_filter(filter, path, mime, term_size)?;
+                let (mut filter_child, capture) =
+                    Self::run_path_filter(filter, path, matched, term_size, mode)?;
                 #[expect(clippy::unwrap_used)]
-                let r = self.dispatch_pipe(filter_child.stdout.take().unwrap(), mode);
+                let r = self.dispatch_pipe(filter_child.stdout.take().unwrap(), mode, 1);
                 filter_child.kill()?;
                 filter_child.wait()?;
+                if let Some(capture) = capture {
+                    capture.finish()?;
+                }
                 r
             }
         }
@@ -501,9 +1424,10 @@ impl HandlerMapping {
     fn run_path_filter(
         filter: &FileFilter,
         path: &Path,
-        mime: Option<&str>,
+        matched: MatchContext,
         term_size: (u16, u16),
-    ) -> Result<Child, HandlerError> {
+        mode: &RsopMode,
+    ) -> Result<(Child, Option<stderr_log::Capture>), HandlerError> {
         let tmp_file = if Self::count_pattern(&filter.command, 't') > 0 {
             Some(tempfile::NamedTempFile::new()?)
         } else {
@@ -514,18 +1438,20 @@ impl HandlerMapping {
         } else {
             None
         };
-        let cmd = Self::substitute(
-            &filter.command,
+        let subst = Substitutions {
             path,
-            mime,
+            input: Some(path),
+            url: None,
+            matched,
             term_size,
-            tmp_file.as_ref(),
-            tmp_dir.as_ref(),
-        )?;
-        let cmd_args = Self::build_cmd(&cmd, filter.shell)?;
+            tmp_file: tmp_file.as_ref(),
+            tmp_dir: tmp_dir.as_ref(),
+        };
+        let cmd_args = Self::build_cmd(&filter.command, filter.shell, &subst)?;
 
         let mut command = Command::new(&cmd_args[0]);
-        command
+        Self::configure_command(&mut command, &filter.process, &subst, mode, 0)?;
+        let mut child = command
             .args(&cmd_args[1..])
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
@@ -533,14 +1459,18 @@ impl HandlerMapping {
             .map_err(|e| HandlerError::Start {
                 err: e,
                 cmd: cmd_args.clone(),
-            })
+            })?;
+        let capture = Self::start_capture(&mut child, &cmd_args)?;
+        Ok((child, capture))
     }
 
     fn run_path_handler(
         handler: &FileHandler,
         path: &Path,
-        mime: Option<&str>,
+        matched: MatchContext,
         term_size: (u16, u16),
+        mode: &RsopMode,
+        terminal_command: Option<&str>,
     ) -> Result<(), HandlerError> {
         let tmp_file = if Self::count_pattern(&handler.command, 't') > 0 {
             Some(tempfile::NamedTempFile::new()?)
@@ -552,37 +1482,57 @@ impl HandlerMapping {
         } else {
             None
         };
-        let cmd = Self::substitute(
-            &handler.command,
+        let subst = Substitutions {
             path,
-            mime,
+            input: Some(path),
+            url: None,
+            matched,
             term_size,
-            tmp_file.as_ref(),
-            tmp_dir.as_ref(),
-        )?;
-        let cmd_args = Self::build_cmd(&cmd, handler.shell)?;
+            tmp_file: tmp_file.as_ref(),
+            tmp_dir: tmp_dir.as_ref(),
+        };
+        let mut cmd_args = Self::build_cmd(&handler.command, handler.shell, &subst)?;
+        if handler.terminal {
+            if stdin().is_terminal() && io::stdout().is_terminal() {
+                log::trace!("Attached to a terminal, not using terminal command");
+            } else if let Some(terminal_command) = terminal_command {
+                cmd_args = Self::terminal_cmd(terminal_command, cmd_args, &subst)?;
+            } else {
+                log::warn!(
+                    "Handler {:?} has 'terminal = true', but terminal_command is not set, running it as is",
+                    handler.command
+                );
+            }
+        }
 
         let mut command = Command::new(&cmd_args[0]);
+        Self::configure_command(&mut command, &handler.process, &subst, mode, 0)?;
         command.args(&cmd_args[1..]).stdin(Stdio::null());
+        if !handler.wait {
+            Self::detach(
+                &mut command,
+                handler.log_file.as_deref(),
+                &handler.process,
+                &cmd_args,
+                &subst,
+            )?;
+        }
+        let start = Instant::now();
+        let mut child = command.spawn().map_err(|e| HandlerError::Start {
+            err: e,
+            cmd: cmd_args.clone(),
+        })?;
+        let target = Some(path.to_string_lossy());
         if handler.wait {
-            command
-                .status()
-                .map(|_| ())
-                .map_err(|e| HandlerError::Start {
-                    err: e,
-                    cmd: cmd_args.clone(),
-                })
+            let capture = Self::start_capture(&mut child, &cmd_args)?;
+            let status = child.wait()?;
+            let run = Some((start, status));
+            Self::record_activity(mode, target, subst.matched, &cmd_args, run);
+            Self::check_exit(status, &cmd_args, capture, mode)?;
         } else {
-            command.stdout(Stdio::null());
-            command.stderr(Stdio::null());
-            command
-                .spawn()
-                .map(|_| ())
-                .map_err(|e| HandlerError::Start {
-                    err: e,
-                    cmd: cmd_args.clone(),
-                })
+            Self::record_activity(mode, target, subst.matched, &cmd_args, None);
         }
+        Ok(())
     }
 
     fn run_pipe<T>(
@@ -590,8 +1540,9 @@ impl HandlerMapping {
         processor: &FileProcessor,
         header: &[u8],
         pipe: T,
-        mime: Option<&str>,
+        matched: MatchContext,
         mode: &RsopMode,
+        filter_depth: usize,
     ) -> Result<(), HandlerError>
     where
         T: Read + Send,
@@ -599,9 +1550,15 @@ impl HandlerMapping {
         let term_size = Self::term_size();
 
         match processor {
-            FileProcessor::Handler(handler) => {
-                Self::run_pipe_handler(handler, header, pipe, mime, term_size)
-            }
+            FileProcessor::Handler(handler) => Self::run_pipe_handler(
+                handler,
+                header,
+                pipe,
+                matched,
+                term_size,
+                mode,
+                filter_depth,
+            ),
             FileProcessor::Filter(filter) => crossbeam_utils::thread::scope(|scope| {
                 // Write to a temporary file if filter does not support reading from stdin
                 let input = if filter.no_pipe {
@@ -616,7 +1573,14 @@ impl HandlerMapping {
                 } else {
                     None
                 };
-                let mut filter_child = Self::run_pipe_filter(filter, mime, tmp_file, term_size)?;
+                let (mut filter_child, capture) = Self::run_pipe_filter(
+                    filter,
+                    matched,
+                    tmp_file,
+                    term_size,
+                    mode,
+                    filter_depth,
+                )?;
                 #[expect(clippy::unwrap_used)]
                 let filter_child_stdout = filter_child.stdout.take().unwrap();
 
@@ -631,11 +1595,14 @@ impl HandlerMapping {
                 }
 
                 // Dispatch to next handler/filter
-                let r = self.dispatch_pipe(filter_child_stdout, mode);
+                let r = self.dispatch_pipe(filter_child_stdout, mode, filter_depth + 1);
 
                 // Cleanup
                 filter_child.kill()?;
                 filter_child.wait()?;
+                if let Some(capture) = capture {
+                    capture.finish()?;
+                }
 
                 r
             })
@@ -645,10 +1612,12 @@ impl HandlerMapping {
 
     fn run_pipe_filter(
         filter: &FileFilter,
-        mime: Option<&str>,
+        matched: MatchContext,
         tmp_file: Option<&tempfile::NamedTempFile>,
         term_size: (u16, u16),
-    ) -> Result<Child, HandlerError> {
+        mode: &RsopMode,
+        filter_depth: usize,
+    ) -> Result<(Child, Option<stderr_log::Capture>), HandlerError> {
         // Build command
         let path = if let Some(tmp_file) = tmp_file {
             tmp_file.path().to_path_buf()
@@ -667,18 +1636,20 @@ impl HandlerMapping {
         } else {
             None
         };
-        let cmd = Self::substitute(
-            &filter.command,
-            &path,
-            mime,
+        let subst = Substitutions {
+            path: &path,
+            input: None,
+            url: None,
+            matched,
             term_size,
-            tmp_file2.as_ref(),
-            tmp_dir.as_ref(),
-        )?;
-        let cmd_args = Self::build_cmd(&cmd, filter.shell)?;
+            tmp_file: tmp_file2.as_ref(),
+            tmp_dir: tmp_dir.as_ref(),
+        };
+        let cmd_args = Self::build_cmd(&filter.command, filter.shell, &subst)?;
 
         // Run
         let mut command = Command::new(&cmd_args[0]);
+        Self::configure_command(&mut command, &filter.process, &subst, mode, filter_depth)?;
         command.args(&cmd_args[1..]);
         if tmp_file.is_none() {
             command.stdin(Stdio::piped());
@@ -688,19 +1659,22 @@ impl HandlerMapping {
                 .env("RSOP_INPUT_IS_STDIN_COPY", "1");
         }
         command.stdout(Stdio::piped());
-        let child = command.spawn().map_err(|e| HandlerError::Start {
+        let mut child = command.spawn().map_err(|e| HandlerError::Start {
             err: e,
             cmd: cmd_args.clone(),
         })?;
-        Ok(child)
+        let capture = Self::start_capture(&mut child, &cmd_args)?;
+        Ok((child, capture))
     }
 
     fn run_pipe_handler<T>(
         handler: &FileHandler,
         header: &[u8],
         pipe: T,
-        mime: Option<&str>,
+        matched: MatchContext,
         term_size: (u16, u16),
+        mode: &RsopMode,
+        filter_depth: usize,
     ) -> Result<(), HandlerError>
     where
         T: Read,
@@ -748,18 +1722,20 @@ impl HandlerMapping {
         } else {
             None
         };
-        let cmd = Self::substitute(
-            &handler.command,
-            &path,
-            mime,
+        let subst = Substitutions {
+            path: &path,
+            input: None,
+            url: None,
+            matched,
             term_size,
-            tmp_file2.as_ref(),
-            tmp_dir.as_ref(),
-        )?;
-        let cmd_args = Self::build_cmd(&cmd, handler.shell)?;
+            tmp_file: tmp_file2.as_ref(),
+            tmp_dir: tmp_dir.as_ref(),
+        };
+        let cmd_args = Self::build_cmd(&handler.command, handler.shell, &subst)?;
 
         // Run
         let mut command = Command::new(&cmd_args[0]);
+        Self::configure_command(&mut command, &handler.process, &subst, mode, filter_depth)?;
         command.args(&cmd_args[1..]);
         if let PipeOrTmpFile::Pipe(_) = input {
             command.stdin(Stdio::piped());
@@ -769,13 +1745,24 @@ impl HandlerMapping {
                 .env("RSOP_INPUT_IS_STDIN_COPY", "1");
         }
         if !handler.wait {
-            command.stdout(Stdio::null());
-            command.stderr(Stdio::null());
+            Self::detach(
+                &mut command,
+                handler.log_file.as_deref(),
+                &handler.process,
+                &cmd_args,
+                &subst,
+            )?;
         }
+        let start = Instant::now();
         let mut child = command.spawn().map_err(|e| HandlerError::Start {
             err: e,
             cmd: cmd_args.clone(),
         })?;
+        let capture = if handler.wait {
+            Self::start_capture(&mut child, &cmd_args)?
+        } else {
+            None
+        };
 
         #[expect(clippy::shadow_unrelated)]
         if let PipeOrTmpFile::Pipe(mut pipe) = input {
@@ -787,13 +1774,22 @@ impl HandlerMapping {
         }
 
         if handler.wait {
-            child.wait()?;
+            let status = child.wait()?;
+            let run = Some((start, status));
+            Self::record_activity(mode, None, subst.matched, &cmd_args, run);
+            Self::check_exit(status, &cmd_args, capture, mode)?;
+        } else {
+            Self::record_activity(mode, None, subst.matched, &cmd_args, None);
         }
 
         Ok(())
     }
 
-    fn run_url(handler: &SchemeHandler, url: &url::Url) -> Result<(), HandlerError> {
+    fn run_url(
+        handler: &SchemeHandler,
+        url: &url::Url,
+        mode: &RsopMode,
+    ) -> Result<(), HandlerError> {
         let term_size = Self::term_size();
 
         // Build command
@@ -808,31 +1804,116 @@ impl HandlerMapping {
         } else {
             None
         };
-        let cmd = Self::substitute(
-            &handler.command,
-            &path,
-            None,
+        let subst = Substitutions {
+            path: &path,
+            input: None,
+            url: Some(url),
+            matched: MatchContext::default(),
             term_size,
-            tmp_file.as_ref(),
-            tmp_dir.as_ref(),
-        )?;
-        let cmd_args = Self::build_cmd(&cmd, handler.shell)?;
+            tmp_file: tmp_file.as_ref(),
+            tmp_dir: tmp_dir.as_ref(),
+        };
+        let cmd_args = Self::build_cmd(&handler.command, handler.shell, &subst)?;
 
         // Run
         let mut command = Command::new(&cmd_args[0]);
+        Self::configure_command(&mut command, &handler.process, &subst, mode, 0)?;
         command.args(&cmd_args[1..]);
         // To mimic xdg-open, close all input/outputs and detach
         command.stdin(Stdio::null());
-        command.stdout(Stdio::null());
-        command.stderr(Stdio::null());
+        Self::detach(
+            &mut command,
+            handler.log_file.as_deref(),
+            &handler.process,
+            &cmd_args,
+            &subst,
+        )?;
         command.spawn().map_err(|e| HandlerError::Start {
             err: e,
             cmd: cmd_args.clone(),
         })?;
+        let target = Some(Cow::Borrowed(url.as_str()));
+        Self::record_activity(mode, target, subst.matched, &cmd_args, None);
 
         Ok(())
     }
 
+    /// Run fetch command of a scheme handler, and dispatch its output like piped data
+    fn fetch_url(
+        &self,
+        handler: &SchemeHandler,
+        url: &url::Url,
+        mode: &RsopMode,
+    ) -> Result<(), HandlerError> {
+        let term_size = Self::term_size();
+
+        // Build command
+        let path: PathBuf = PathBuf::from(url.as_str());
+        let tmp_file = if Self::count_pattern(&handler.command, 't') > 0 {
+            Some(tempfile::NamedTempFile::new()?)
+        } else {
+            None
+        };
+        let tmp_dir = if Self::count_pattern(&handler.command, 'T') > 0 {
+            Some(tempfile::tempdir()?)
+        } else {
+            None
+        };
+        let subst = Substitutions {
+            path: &path,
+            input: None,
+            url: Some(url),
+            matched: MatchContext::default(),
+            term_size,
+            tmp_file: tmp_file.as_ref(),
+            tmp_dir: tmp_dir.as_ref(),
+        };
+        let cmd_args = Self::build_cmd(&handler.command, handler.shell, &subst)?;
+
+        // Run
+        let mut command = Command::new(&cmd_args[0]);
+        Self::configure_command(&mut command, &handler.process, &subst, mode, 0)?;
+        command.args(&cmd_args[1..]);
+        command.stdin(Stdio::null()).stdout(Stdio::piped());
+        let mut child = command.spawn().map_err(|e| HandlerError::Start {
+            err: e,
+            cmd: cmd_args.clone(),
+        })?;
+        let capture = Self::start_capture(&mut child, &cmd_args)?;
+        #[expect(clippy::unwrap_used)]
+        let mut child_stdout = child.stdout.take().unwrap();
+
+        // Read header first, to report a failed fetch rather than open its empty output
+        let mut header: Vec<u8> = vec![0; PIPE_INITIAL_READ_LENGTH];
+        let header_len = child_stdout.read(&mut header)?;
+        header.truncate(header_len);
+        if header.is_empty() {
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(HandlerError::Exit {
+                    status,
+                    cmd: cmd_args,
+                    stderr: capture
+                        .map(stderr_log::Capture::finish)
+                        .transpose()?
+                        .unwrap_or_default(),
+                });
+            }
+        }
+
+        // Dispatch to file handler/filter
+        let r = self.dispatch_pipe(io::Cursor::new(header).chain(child_stdout), mode, 0);
+
+        // Cleanup, the fetch command may still be blocked writing output nobody reads
+        if r.is_err() {
+            child.kill()?;
+            child.wait()?;
+            return r;
+        }
+        let status = child.wait()?;
+        Self::check_exit(status, &cmd_args, capture, mode)
+    }
+
     fn stdin_reader() -> File {
         let stdin = stdin();
         // SAFETY:
@@ -893,12 +1974,197 @@ impl HandlerMapping {
         Ok(tmp_file)
     }
 
-    fn build_cmd(cmd: &str, shell: bool) -> anyhow::Result<Vec<String>> {
+    /// Start capturing stderr of a child to the stderr log if it was piped, echoing it to rsop's stderr
+    fn start_capture(
+        child: &mut Child,
+        cmd_args: &[String],
+    ) -> anyhow::Result<Option<stderr_log::Capture>> {
+        child
+            .stderr
+            .take()
+            .map(|stderr| {
+                let log = stderr_log::open(cmd_args)?;
+                Ok(stderr_log::Capture::start(stderr, log, true))
+            })
+            .transpose()
+    }
+
+    /// Check exit status of a handler whose stderr was captured, and report its last lines on failure
+    ///
+    /// Failures of handlers whose stderr was not captured are only reported in xdg-open mode, which has an exit code
+    /// for them.
+    fn check_exit(
+        status: ExitStatus,
+        cmd_args: &[String],
+        capture: Option<stderr_log::Capture>,
+        mode: &RsopMode,
+    ) -> Result<(), HandlerError> {
+        let stderr = capture.map(stderr_log::Capture::finish).transpose()?;
+        if status.success() || (stderr.is_none() && (*mode != RsopMode::XdgOpen)) {
+            Ok(())
+        } else {
+            Err(HandlerError::Exit {
+                status,
+                cmd: cmd_args.to_vec(),
+                stderr: stderr.unwrap_or_default(),
+            })
+        }
+    }
+
+    /// Record a handler run in the activity log, with its start time and exit status if it was waited for
+    fn record_activity(
+        mode: &RsopMode,
+        target: Option<Cow<'_, str>>,
+        matched: MatchContext,
+        cmd_args: &[String],
+        run: Option<(Instant, ExitStatus)>,
+    ) {
+        logger::record_activity(&logger::Activity {
+            time: logger::unix_time(),
+            mode: mode.to_string(),
+            target,
+            mime: matched.mime,
+            filetype: matched.filetype,
+            argv: cmd_args,
+            duration_ms: run.map(|(start, _)| start.elapsed().as_millis()),
+            exit_code: run.and_then(|(_, status)| status.code()),
+        });
+    }
+
+    /// Prefix command arguments with terminal command, to run it in a new terminal window
+    fn terminal_cmd(
+        terminal_command: &str,
+        cmd_args: Vec<String>,
+        subst: &Substitutions,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut args = Self::build_cmd(terminal_command, false, subst)?;
+        args.extend(cmd_args);
+        log::debug!("Will run command in a new terminal: {args:?}");
+        Ok(args)
+    }
+
+    /// Detach a command that is not waited for from rsop's session, so that it is not killed when the terminal is
+    /// closed, and redirect its output to a log file, or discard it
+    fn detach(
+        command: &mut Command,
+        log_file: Option<&str>,
+        options: &ProcessOptions,
+        cmd_args: &[String],
+        subst: &Substitutions,
+    ) -> anyhow::Result<()> {
+        if let Some(log_file) = log_file {
+            let log_path = Self::expand_path(log_file, subst)?;
+            if let Some(log_dir) = log_path.parent() {
+                fs::create_dir_all(log_dir)
+                    .with_context(|| format!("Failed to create directory {log_dir:?}"))?;
+            }
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(&log_path)
+                .with_context(|| format!("Failed to open log file {log_path:?}"))?;
+            log::debug!("Handler output will be appended to {log_path:?}");
+            command.stdout(file.try_clone()?);
+            command.stderr(file);
+        } else {
+            command.stdout(Stdio::null());
+            command.stderr(Stdio::null());
+        }
+        if options.capture_stderr {
+            command.stderr(stderr_log::open(cmd_args)?);
+        }
+        // SAFETY:
+        // setsid is async-signal-safe, and does not allocate
+        unsafe {
+            command.pre_exec(|| {
+                nix::unistd::setsid()?;
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Expand environment variables and substitute patterns in a path from a handler option
+    fn expand_path(template: &str, subst: &Substitutions) -> anyhow::Result<PathBuf> {
+        let expanded = Self::expand_path_vars(template, |name| env::var(name).ok())?;
+        Ok(PathBuf::from(Self::substitute(&expanded, subst)?))
+    }
+
+    /// Apply process options and context environment variables to a command
+    fn configure_command(
+        command: &mut Command,
+        options: &ProcessOptions,
+        subst: &Substitutions,
+        mode: &RsopMode,
+        filter_depth: usize,
+    ) -> anyhow::Result<()> {
+        if options.clear_env {
+            command.env_clear();
+        }
+        subst.set_env(command, mode, filter_depth)?;
+        command.envs(&options.env);
+        if options.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+        if let Some(cwd) = &options.cwd {
+            let cwd = Self::expand_path(cwd, subst)?;
+            if cwd.as_os_str().is_empty() {
+                log::debug!("Working directory {:?} is empty, ignoring it", options.cwd);
+            } else {
+                log::debug!("Will run command in directory {cwd:?}");
+                command.current_dir(cwd);
+            }
+        }
+        if let Some(umask) = options.umask {
+            let umask = nix::sys::stat::Mode::from_bits_truncate(umask.into());
+            // SAFETY:
+            // umask is async-signal-safe, and does not allocate
+            unsafe {
+                command.pre_exec(move || {
+                    nix::sys::stat::umask(umask);
+                    Ok(())
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Build command arguments from command template
+    ///
+    /// Without shell, the template is split into arguments first, and patterns are then substituted in each of them,
+    /// so substituted values are never split or interpreted.
+    fn build_cmd(command: &str, shell: bool, subst: &Substitutions) -> anyhow::Result<Vec<String>> {
         let cmd = if shell {
-            vec!["sh".to_owned(), "-c".to_owned(), cmd.to_owned()]
+            let (script, params) = Self::shell_script(command, subst)?;
+            let mut cmd = vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                script,
+                env!("CARGO_PKG_NAME").to_owned(),
+            ];
+            cmd.extend(params);
+            cmd
         } else {
-            shlex::split(cmd).ok_or_else(|| anyhow::anyhow!("Invalid command {cmd:?}"))?
+            let expanded = Self::expand_env(command)?;
+            let tokens = shlex::split(&expanded)
+                .ok_or_else(|| anyhow::anyhow!("Invalid command {command:?}"))?;
+            let mut args = Vec::with_capacity(tokens.len());
+            for token in tokens {
+                let arg = Self::substitute(&token, subst)?;
+                // Drop arguments that only contained patterns substituted with nothing (ie. stdin_arg = ""), except
+                // for missing URL components, which would otherwise shift the following arguments
+                if !arg.is_empty()
+                    || token.is_empty()
+                    || Self::parse_template(&token)
+                        .iter()
+                        .any(TemplatePart::is_url_component)
+                {
+                    args.push(arg);
+                }
+            }
+            args
         };
+        anyhow::ensure!(!cmd.is_empty(), "Empty command {command:?}");
         log::debug!("Will run command: {cmd:?}");
         Ok(cmd)
     }
@@ -965,6 +2231,10 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: Some(String::new()),
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
         let mut processor = FileProcessor::Handler(handler.clone());
         assert!(!processor.has_pattern('m'));
@@ -996,21 +2266,46 @@ mod tests {
         assert_eq!(HandlerMapping::count_pattern("a %%i %i %%m", 'i'), 1);
     }
 
+    fn substitute_with(
+        s: &str,
+        path: &Path,
+        mime: Option<&str>,
+        term_size: (u16, u16),
+        tmp_file: Option<&tempfile::NamedTempFile>,
+        tmp_dir: Option<&tempfile::TempDir>,
+    ) -> anyhow::Result<String> {
+        HandlerMapping::substitute(
+            s,
+            &Substitutions {
+                path,
+                input: None,
+                url: None,
+                matched: MatchContext {
+                    mime,
+                    ..MatchContext::default()
+                },
+                term_size,
+                tmp_file,
+                tmp_dir,
+            },
+        )
+    }
+
     #[test]
     fn substitute() {
         let term_size = (85, 84);
         let path = Path::new("");
 
         assert_eq!(
-            HandlerMapping::substitute("abc def", path, None, term_size, None, None).unwrap(),
+            substitute_with("abc def", path, None, term_size, None, None).unwrap(),
             "abc def"
         );
         assert_eq!(
-            HandlerMapping::substitute("ab%%c def", path, None, term_size, None, None).unwrap(),
+            substitute_with("ab%%c def", path, None, term_size, None, None).unwrap(),
             "ab%c def"
         );
         assert_eq!(
-            HandlerMapping::substitute("ab%c def", path, None, term_size, None, None).unwrap(),
+            substitute_with("ab%c def", path, None, term_size, None, None).unwrap(),
             "ab85 def"
         );
     }
@@ -1122,7 +2417,7 @@ mod tests {
         let path = Path::new("/tmp/test.txt");
 
         assert_eq!(
-            HandlerMapping::substitute("cat %i", path, None, term_size, None, None).unwrap(),
+            substitute_with("cat %i", path, None, term_size, None, None).unwrap(),
             "cat /tmp/test.txt"
         );
     }
@@ -1133,8 +2428,7 @@ mod tests {
         let path = Path::new("/tmp/test.txt");
 
         assert_eq!(
-            HandlerMapping::substitute("echo %m", path, Some("text/plain"), term_size, None, None)
-                .unwrap(),
+            substitute_with("echo %m", path, Some("text/plain"), term_size, None, None).unwrap(),
             "echo text/plain"
         );
     }
@@ -1145,12 +2439,11 @@ mod tests {
         let path = Path::new("");
 
         assert_eq!(
-            HandlerMapping::substitute("head -n %l %i", path, None, term_size, None, None).unwrap(),
-            "head -n 40"
+            substitute_with("head -n %l %i", path, None, term_size, None, None).unwrap(),
+            "head -n 40 "
         );
         assert_eq!(
-            HandlerMapping::substitute("cols=%c lines=%l", path, None, term_size, None, None)
-                .unwrap(),
+            substitute_with("cols=%c lines=%l", path, None, term_size, None, None).unwrap(),
             "cols=120 lines=40"
         );
     }
@@ -1161,20 +2454,19 @@ mod tests {
         let path = Path::new("");
 
         assert_eq!(
-            HandlerMapping::substitute("a %%i b", path, None, term_size, None, None).unwrap(),
+            substitute_with("a %%i b", path, None, term_size, None, None).unwrap(),
             "a %i b"
         );
         assert_eq!(
-            HandlerMapping::substitute("a %%c b", path, None, term_size, None, None).unwrap(),
+            substitute_with("a %%c b", path, None, term_size, None, None).unwrap(),
             "a %c b"
         );
         assert_eq!(
-            HandlerMapping::substitute("a %%l b", path, None, term_size, None, None).unwrap(),
+            substitute_with("a %%l b", path, None, term_size, None, None).unwrap(),
             "a %l b"
         );
         assert_eq!(
-            HandlerMapping::substitute("a %%m b", path, Some("text/plain"), term_size, None, None)
-                .unwrap(),
+            substitute_with("a %%m b", path, Some("text/plain"), term_size, None, None).unwrap(),
             "a %m b"
         );
     }
@@ -1185,7 +2477,7 @@ mod tests {
         let path = Path::new("/tmp/file.txt");
 
         assert_eq!(
-            HandlerMapping::substitute(
+            substitute_with(
                 "bat -n --terminal-width %c -r :%l %i",
                 path,
                 None,
@@ -1204,7 +2496,7 @@ mod tests {
         let path = Path::new("/tmp/a.txt");
 
         assert_eq!(
-            HandlerMapping::substitute("echo %i %i", path, None, term_size, None, None).unwrap(),
+            substitute_with("echo %i %i", path, None, term_size, None, None).unwrap(),
             "echo /tmp/a.txt /tmp/a.txt"
         );
     }
@@ -1214,9 +2506,75 @@ mod tests {
         let term_size = (80, 24);
         let path = Path::new("/tmp/my file.txt");
 
-        let result =
-            HandlerMapping::substitute("cat %i", path, None, term_size, None, None).unwrap();
-        assert_eq!(result, "cat '/tmp/my file.txt'");
+        let result = substitute_with("cat %i", path, None, term_size, None, None).unwrap();
+        // Not quoted, because substitution is done after splitting arguments
+        assert_eq!(result, "cat /tmp/my file.txt");
+    }
+
+    fn test_lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/user".to_owned()),
+            "EDITOR" => Some("vim".to_owned()),
+            "SPACED" => Some("a b".to_owned()),
+            "PERCENT" => Some("50%i".to_owned()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_vars() {
+        let expand = |s| HandlerMapping::expand_vars(s, test_lookup).unwrap();
+        assert_eq!(expand("$EDITOR %i"), "vim %i");
+        assert_eq!(expand("${EDITOR} %i"), "vim %i");
+        assert_eq!(expand("${VISUAL:-vi} %i"), "vi %i");
+        assert_eq!(expand("${EMPTY:-vi} %i"), "vi %i");
+        assert_eq!(expand("${EDITOR:-vi} %i"), "vim %i");
+        assert_eq!(expand("a $UNSET b"), "a  b");
+        assert_eq!(
+            expand("~/bin/viewer ~ a~ ~user"),
+            "/home/user/bin/viewer /home/user a~ ~user"
+        );
+        assert_eq!(expand("echo '$EDITOR ~'"), "echo '$EDITOR ~'");
+        assert_eq!(expand("echo \"$EDITOR ~\""), "echo \"vim ~\"");
+        assert_eq!(expand("echo \\$EDITOR $ 5$"), "echo \\$EDITOR $ 5$");
+        assert!(HandlerMapping::expand_vars("echo ${EDITOR", test_lookup).is_err());
+        assert!(HandlerMapping::expand_vars("echo ${1}", test_lookup).is_err());
+    }
+
+    #[test]
+    fn expand_vars_invalid_name_literal() {
+        let expand = |s| HandlerMapping::expand_vars(s, test_lookup).unwrap();
+        assert_eq!(expand("echo $1 $5abc $0 a$-b"), "echo $1 $5abc $0 a$-b");
+        assert_eq!(expand("echo \"$1x\" $5$EDITOR"), "echo \"$1x\" $5vim");
+
+        let subst = test_subst(Path::new("/tmp/test.txt"), None);
+        assert_eq!(
+            HandlerMapping::build_cmd(&expand("printf $1 $5abc %i"), false, &subst).unwrap(),
+            vec!["printf", "$1", "$5abc", "/tmp/test.txt"]
+        );
+    }
+
+    #[test]
+    fn expand_vars_no_splitting() {
+        let expand = |s| HandlerMapping::expand_vars(s, test_lookup).unwrap();
+        assert_eq!(
+            shlex::split(&expand("cmd $SPACED x${SPACED}y \"$SPACED\"")).unwrap(),
+            vec!["cmd", "a b", "xa by", "a b"]
+        );
+        assert_eq!(expand("cmd $PERCENT \"$PERCENT\""), "cmd '50%%i' \"50%%i\"");
+        assert_eq!(
+            substitute_with(
+                &expand("cmd $PERCENT %i"),
+                Path::new("f"),
+                None,
+                (80, 24),
+                None,
+                None
+            )
+            .unwrap(),
+            "cmd '50%i' f"
+        );
     }
 
     #[test]
@@ -1225,9 +2583,7 @@ mod tests {
         let path = Path::new("/tmp/test.txt");
         let tmp = tempfile::NamedTempFile::new().unwrap();
 
-        let result =
-            HandlerMapping::substitute("cp %i %t", path, None, term_size, Some(&tmp), None)
-                .unwrap();
+        let result = substitute_with("cp %i %t", path, None, term_size, Some(&tmp), None).unwrap();
         assert!(result.starts_with("cp /tmp/test.txt "));
         assert!(result.contains(tmp.path().to_str().unwrap()));
     }
@@ -1239,89 +2595,694 @@ mod tests {
         let tmp_dir = tempfile::tempdir().unwrap();
 
         let result =
-            HandlerMapping::substitute("cp %i %T", path, None, term_size, None, Some(&tmp_dir))
-                .unwrap();
+            substitute_with("cp %i %T", path, None, term_size, None, Some(&tmp_dir)).unwrap();
         assert!(result.starts_with("cp /tmp/test.txt "));
         assert!(result.contains(tmp_dir.path().to_str().unwrap()));
     }
 
+    fn substitute_input(s: &str, input: &Path, matched: MatchContext) -> anyhow::Result<String> {
+        HandlerMapping::substitute(
+            s,
+            &Substitutions {
+                path: input,
+                input: Some(input),
+                url: None,
+                matched,
+                term_size: (80, 24),
+                tmp_file: None,
+                tmp_dir: None,
+            },
+        )
+    }
+
+    #[test]
+    fn substitute_path_parts() {
+        let path = Path::new("/tmp/some dir/archive.tar.gz");
+        let matched = MatchContext {
+            mime: None,
+            filetype: Some("archive"),
+            extension: Some("tar.gz"),
+        };
+
+        assert_eq!(
+            substitute_input(
+                "%{filename}|%{dir}|%{stem}|%{ext}|%{filetype}",
+                path,
+                matched
+            )
+            .unwrap(),
+            "archive.tar.gz|/tmp/some dir|archive|tar.gz|archive"
+        );
+        assert_eq!(
+            substitute_input(
+                "%%{filename} %%{dir} %%{stem} %%{ext} %%{filetype}",
+                path,
+                matched
+            )
+            .unwrap(),
+            "%{filename} %{dir} %{stem} %{ext} %{filetype}"
+        );
+    }
+
+    #[test]
+    fn substitute_literal_percent() {
+        let path = Path::new("/tmp/a.txt");
+        let matched = MatchContext::default();
+
+        assert_eq!(
+            substitute_input("date +%d %s %n %{unknown} %{stem", path, matched).unwrap(),
+            "date +%d %s %n %{unknown} %{stem"
+        );
+        assert_eq!(
+            HandlerMapping::parse_template("%{stem}.%{ext}%%{size}"),
+            vec![
+                TemplatePart::Pattern('s'),
+                TemplatePart::Text(".".to_owned()),
+                TemplatePart::Pattern('e'),
+                TemplatePart::Text("%{size}".to_owned()),
+            ]
+        );
+        assert_eq!(
+            HandlerMapping::escape_patterns("100%{size} %s"),
+            "100%%{size} %s"
+        );
+    }
+
+    #[test]
+    fn substitute_path_parts_no_match() {
+        let matched = MatchContext::default();
+
+        assert_eq!(
+            substitute_input(
+                "%{filename}|%{dir}|%{stem}|%{ext}|%{filetype}",
+                Path::new("archive.tar.gz"),
+                matched
+            )
+            .unwrap(),
+            "archive.tar.gz|.|archive.tar||"
+        );
+        assert_eq!(
+            substitute_input("%{stem}", Path::new("/tmp/README"), matched).unwrap(),
+            "README"
+        );
+        assert_eq!(
+            substitute_input("%{stem}", Path::new("/tmp/.bashrc"), matched).unwrap(),
+            ".bashrc"
+        );
+        assert_eq!(
+            substitute_input("%{stem}", Path::new("/tmp/.bashrc.txt"), matched).unwrap(),
+            ".bashrc"
+        );
+    }
+
+    #[test]
+    fn substitute_size_mtime() {
+        let mut tmp_file = tempfile::NamedTempFile::new().unwrap();
+        tmp_file.write_all(b"12345").unwrap();
+        let mtime = tmp_file
+            .as_file()
+            .metadata()
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        assert_eq!(
+            substitute_input("%{size} %{mtime}", tmp_file.path(), MatchContext::default()).unwrap(),
+            format!("5 {mtime}")
+        );
+        assert!(
+            substitute_input(
+                "%{size}",
+                Path::new("/nonexistent"),
+                MatchContext::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn substitute_path_parts_pipe() {
+        assert_eq!(
+            substitute_with(
+                "cat %i %{filename}%{dir}%{stem}%{size}%{mtime}",
+                Path::new("-"),
+                None,
+                (80, 24),
+                None,
+                None
+            )
+            .unwrap(),
+            "cat - "
+        );
+    }
+
+    #[test]
+    fn build_cmd_path_parts_quoted() {
+        let path = Path::new("/tmp/it's a \"dir\"/a b.txt");
+        let subst = Substitutions {
+            matched: MatchContext {
+                extension: Some("txt"),
+                ..MatchContext::default()
+            },
+            ..test_subst(path, None)
+        };
+
+        assert_eq!(
+            HandlerMapping::build_cmd("mv %i %{dir}/%{stem}.md", false, &subst).unwrap(),
+            vec![
+                "mv",
+                "/tmp/it's a \"dir\"/a b.txt",
+                "/tmp/it's a \"dir\"/a b.md"
+            ]
+        );
+        let cmd = HandlerMapping::build_cmd("echo %{filename} | tr a A", true, &subst).unwrap();
+        assert_eq!(cmd[2], "echo \"${1}\" | tr a A");
+        assert_eq!(cmd[4], "a b.txt");
+    }
+
+    #[test]
+    fn set_env() {
+        let path = Path::new("/tmp/a.txt");
+        let subst = Substitutions {
+            matched: MatchContext {
+                mime: Some("text/plain"),
+                filetype: Some("text"),
+                extension: Some("txt"),
+            },
+            ..test_subst(path, None)
+        };
+        let mut command = Command::new("true");
+        subst.set_env(&mut command, &RsopMode::Preview, 2).unwrap();
+        let envs: HashMap<_, _> = command
+            .get_envs()
+            .map(|(k, v)| (k.to_str().unwrap(), v.and_then(OsStr::to_str)))
+            .collect();
+        assert_eq!(
+            envs,
+            HashMap::from([
+                ("RSOP_HANDLER_MODE", Some("preview")),
+                ("RSOP_MIME", Some("text/plain")),
+                ("RSOP_FILETYPE", Some("text")),
+                ("RSOP_PATH", Some("/tmp/a.txt")),
+                ("RSOP_COLUMNS", Some("80")),
+                ("RSOP_LINES", Some("24")),
+                ("RSOP_FILTER_DEPTH", Some("2")),
+            ])
+        );
+    }
+
+    #[test]
+    fn set_env_pipe() {
+        let subst = Substitutions {
+            input: None,
+            ..test_subst(Path::new("-"), None)
+        };
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "echo \"$RSOP_HANDLER_MODE|${RSOP_PATH-unset}|${RSOP_MIME-unset}\"",
+        ]);
+        command.env("RSOP_PATH", "/parent/path");
+        subst.set_env(&mut command, &RsopMode::XdgOpen, 0).unwrap();
+        let output = command.output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "xdg-open|unset|unset\n"
+        );
+    }
+
+    #[test]
+    fn configure_command() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("a.txt");
+        let subst = test_subst(&path, None);
+        let options = ProcessOptions {
+            env: HashMap::from([("FOO".to_owned(), "bar baz".to_owned())]),
+            cwd: Some("%{dir}".to_owned()),
+            clear_env: true,
+            umask: Some(0o027),
+            capture_stderr: false,
+        };
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "echo \"$FOO|${HOME-unset}|$RSOP_HANDLER_MODE|$(pwd)|$(umask)\"",
+        ]);
+        HandlerMapping::configure_command(&mut command, &options, &subst, &RsopMode::Open, 0)
+            .unwrap();
+        let output = command.output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(
+                "bar baz|unset|open|{}|0027\n",
+                tmp_dir.path().canonicalize().unwrap().to_str().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn detach_log_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("a.txt");
+        let subst = test_subst(&path, None);
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2; cat /proc/$$/stat"]);
+        HandlerMapping::detach(
+            &mut command,
+            Some("%{dir}/logs/%{stem}.log"),
+            &ProcessOptions::default(),
+            &[],
+            &subst,
+        )
+        .unwrap();
+        command.status().unwrap();
+
+        let log = fs::read_to_string(tmp_dir.path().join("logs/a.log")).unwrap();
+        let mut lines = log.lines();
+        assert_eq!(lines.next(), Some("out"));
+        assert_eq!(lines.next(), Some("err"));
+        // Child is session leader: session id (6th field of stat) is its pid (1st field)
+        let stat: Vec<_> = lines.next().unwrap().split(' ').collect();
+        assert_eq!(stat[0], stat[5]);
+        assert_ne!(
+            stat[5],
+            nix::unistd::getsid(None).unwrap().as_raw().to_string()
+        );
+    }
+
+    #[test]
+    fn check_exit_reports_stderr() {
+        let cmd_args = vec!["sh".to_owned(), "-c".to_owned(), "exit 3".to_owned()];
+        let mut child = Command::new("sh")
+            .args(["-c", "echo first >&2; echo second >&2; exit 3"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let log = tempfile::tempfile().unwrap();
+        let capture = stderr_log::Capture::start(child.stderr.take().unwrap(), log, false);
+        let status = child.wait().unwrap();
+
+        let err = HandlerMapping::check_exit(status, &cmd_args, Some(capture), &RsopMode::Open)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Handler command \"sh -c exit 3\" failed with exit status: 3\n  first\n  second"
+        );
+        assert!(HandlerMapping::check_exit(status, &cmd_args, None, &RsopMode::Open).is_ok());
+        assert!(matches!(
+            HandlerMapping::check_exit(status, &cmd_args, None, &RsopMode::XdgOpen),
+            Err(HandlerError::Exit { .. })
+        ));
+    }
+
+    #[test]
+    fn expand_path() {
+        let subst = test_subst(Path::new("/tmp/a.txt"), None);
+        let home = env::var("HOME").unwrap();
+        assert_eq!(
+            HandlerMapping::expand_path("~/logs/%{filename}.log", &subst).unwrap(),
+            Path::new(&home).join("logs/a.txt.log")
+        );
+        assert_eq!(
+            HandlerMapping::expand_path("%{dir}/with space", &subst).unwrap(),
+            Path::new("/tmp/with space")
+        );
+        assert_eq!(
+            HandlerMapping::expand_path("/tmp/my dir", &subst).unwrap(),
+            Path::new("/tmp/my dir")
+        );
+    }
+
+    #[test]
+    fn expand_path_vars() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/a b".to_owned()),
+            "PCT" => Some("100%i".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            HandlerMapping::expand_path_vars("~/x ${PCT}/$UNSET/${UNSET:-d}/$/~", lookup).unwrap(),
+            "/home/a b/x 100%%i//d/$/~"
+        );
+        assert_eq!(
+            HandlerMapping::expand_path_vars("'~/it's'", lookup).unwrap(),
+            "'~/it's'"
+        );
+        assert_eq!(
+            HandlerMapping::expand_path_vars("/tmp/$1/$5abc", lookup).unwrap(),
+            "/tmp/$1/$5abc"
+        );
+    }
+
+    #[test]
+    fn substitute_no_patterns() {
+        let term_size = (80, 24);
+        let path = Path::new("");
+
+        assert_eq!(
+            substitute_with("plain command here", path, None, term_size, None, None).unwrap(),
+            "plain command here"
+        );
+    }
+
+    #[test]
+    fn substitute_keeps_whitespace() {
+        let term_size = (80, 24);
+        let path = Path::new("");
+
+        assert_eq!(
+            substitute_with("  cmd  ", path, None, term_size, None, None).unwrap(),
+            "  cmd  "
+        );
+    }
+
+    #[test]
+    fn parse_template_query_param() {
+        assert_eq!(
+            HandlerMapping::parse_template(
+                "a %{query:name}b %{query} %{query:} %{query:a b} %q{x} %%{query:x}"
+            ),
+            vec![
+                TemplatePart::Text("a ".to_owned()),
+                TemplatePart::QueryParam("name".to_owned()),
+                TemplatePart::Text("b ".to_owned()),
+                TemplatePart::Pattern('q'),
+                TemplatePart::Text(" %{query:} %{query:a b} %q{x} %{query:x}".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn count_pattern_no_match() {
+        assert_eq!(HandlerMapping::count_pattern("hello world", 'i'), 0);
+        assert_eq!(HandlerMapping::count_pattern("hello world", 'm'), 0);
+        assert_eq!(HandlerMapping::count_pattern("hello world", 'c'), 0);
+        assert_eq!(HandlerMapping::count_pattern("hello world", 'l'), 0);
+    }
+
+    #[test]
+    fn count_pattern_escaped() {
+        assert_eq!(HandlerMapping::count_pattern("a %%i b", 'i'), 0);
+        assert_eq!(HandlerMapping::count_pattern("a %%m b", 'm'), 0);
+    }
+
+    #[test]
+    fn count_pattern_mixed() {
+        assert_eq!(HandlerMapping::count_pattern("a %i b %%i c %i", 'i'), 2);
+    }
+
+    #[test]
+    fn has_pattern_filter() {
+        let filter = FileFilter {
+            command: "gzip -dc %i".to_owned(),
+            shell: false,
+            no_pipe: false,
+            stdin_arg: None,
+            process: ProcessOptions::default(),
+        };
+        let processor = FileProcessor::Filter(filter);
+        assert!(processor.has_pattern('i'));
+        assert!(!processor.has_pattern('m'));
+    }
+
+    fn test_subst<'a>(path: &'a Path, mime: Option<&'a str>) -> Substitutions<'a> {
+        Substitutions {
+            path,
+            input: Some(path),
+            url: None,
+            matched: MatchContext {
+                mime,
+                ..MatchContext::default()
+            },
+            term_size: (80, 24),
+            tmp_file: None,
+            tmp_dir: None,
+        }
+    }
+
+    #[test]
+    fn build_cmd_no_shell() {
+        let subst = test_subst(Path::new("/tmp/test.txt"), None);
+        let cmd = HandlerMapping::build_cmd("cat /tmp/test.txt", false, &subst).unwrap();
+        assert_eq!(cmd, vec!["cat", "/tmp/test.txt"]);
+    }
+
+    #[test]
+    fn build_cmd_shell() {
+        let subst = test_subst(Path::new("/tmp/test.txt"), None);
+        let cmd = HandlerMapping::build_cmd("echo hello | grep h", true, &subst).unwrap();
+        assert_eq!(cmd, vec!["sh", "-c", "echo hello | grep h", "rsop"]);
+    }
+
+    #[test]
+    fn build_cmd_quoted() {
+        let subst = test_subst(Path::new("/tmp/test.txt"), None);
+        let cmd = HandlerMapping::build_cmd("cat '/tmp/my file.txt'", false, &subst).unwrap();
+        assert_eq!(cmd, vec!["cat", "/tmp/my file.txt"]);
+    }
+
+    #[test]
+    fn build_cmd_invalid() {
+        let subst = test_subst(Path::new("/tmp/test.txt"), None);
+        assert!(HandlerMapping::build_cmd("cat 'unclosed", false, &subst).is_err());
+        assert!(HandlerMapping::build_cmd("  ", false, &subst).is_err());
+    }
+
+    #[test]
+    fn build_cmd_no_shell_values_not_split() {
+        let subst = test_subst(Path::new("/tmp/my file's.txt"), Some("text/x-a b"));
+        assert_eq!(
+            HandlerMapping::build_cmd("cmd %i --mime=%m '%i' \"x %c\"", false, &subst).unwrap(),
+            vec![
+                "cmd",
+                "/tmp/my file's.txt",
+                "--mime=text/x-a b",
+                "/tmp/my file's.txt",
+                "x 80"
+            ]
+        );
+    }
+
+    #[test]
+    fn build_cmd_no_shell_empty_value() {
+        let subst = test_subst(Path::new(""), None);
+        assert_eq!(
+            HandlerMapping::build_cmd("hexyl %i ''", false, &subst).unwrap(),
+            vec!["hexyl", ""]
+        );
+    }
+
+    #[test]
+    fn build_cmd_shell_positional_params() {
+        let subst = test_subst(Path::new("/tmp/a $b'c.txt"), Some("text/plain"));
+        assert_eq!(
+            HandlerMapping::build_cmd(
+                "echo '%m: %i' \"%i\" | head -n $((%l - 1)) %i %%i",
+                true,
+                &subst
+            )
+            .unwrap(),
+            vec![
+                "sh",
+                "-c",
+                "echo ''\"${1}\"': '\"${2}\"'' \"${2}\" | head -n $((24 - 1)) \"${2}\" %i",
+                "rsop",
+                "text/plain",
+                "/tmp/a $b'c.txt"
+            ]
+        );
+    }
+
     #[test]
-    fn substitute_no_patterns() {
-        let term_size = (80, 24);
-        let path = Path::new("");
-
+    fn substitute_url_parts() {
+        let url =
+            url::Url::parse("ssh://user%40corp@example.com:2222/srv/a%20b?x=1#frag%21").unwrap();
+        let path = PathBuf::from(url.as_str());
+        let subst = Substitutions {
+            input: None,
+            url: Some(&url),
+            ..test_subst(&path, None)
+        };
         assert_eq!(
-            HandlerMapping::substitute("plain command here", path, None, term_size, None, None)
-                .unwrap(),
-            "plain command here"
+            HandlerMapping::substitute(
+                "%{user}|%{host}|%{port}|%{path}|%{query}|%{fragment}",
+                &subst
+            )
+            .unwrap(),
+            "user@corp|example.com|2222|/srv/a b|x=1|frag!"
         );
-    }
 
-    #[test]
-    fn substitute_trims_whitespace() {
-        let term_size = (80, 24);
-        let path = Path::new("");
+        let https_url = url::Url::parse("https://example.com").unwrap();
+        let https_subst = Substitutions {
+            url: Some(&https_url),
+            ..subst
+        };
+        assert_eq!(
+            HandlerMapping::substitute(
+                "%{user}|%{host}|%{port}|%{path}|%{query}|%{fragment}",
+                &https_subst
+            )
+            .unwrap(),
+            "|example.com|443|/||"
+        );
 
+        // Without URL, patterns are kept as is
+        let file_subst = test_subst(Path::new("a.txt"), None);
+        let url_patterns = "%{user} %{host} %{port} %{path} %{query} %{fragment} %{query:a}";
         assert_eq!(
-            HandlerMapping::substitute("  cmd  ", path, None, term_size, None, None).unwrap(),
-            "cmd"
+            HandlerMapping::substitute(url_patterns, &file_subst).unwrap(),
+            url_patterns
+        );
+        assert_eq!(
+            HandlerMapping::build_cmd(url_patterns, true, &file_subst).unwrap()[2],
+            url_patterns
         );
-    }
 
-    #[test]
-    fn count_pattern_no_match() {
-        assert_eq!(HandlerMapping::count_pattern("hello world", 'i'), 0);
-        assert_eq!(HandlerMapping::count_pattern("hello world", 'm'), 0);
-        assert_eq!(HandlerMapping::count_pattern("hello world", 'c'), 0);
-        assert_eq!(HandlerMapping::count_pattern("hello world", 'l'), 0);
+        // Single letters are no longer patterns, ie. for printf
+        assert_eq!(
+            HandlerMapping::substitute("%u %h %p %P %q %F", &subst).unwrap(),
+            "%u %h %p %P %q %F"
+        );
     }
 
     #[test]
-    fn count_pattern_escaped() {
-        assert_eq!(HandlerMapping::count_pattern("a %%i b", 'i'), 0);
-        assert_eq!(HandlerMapping::count_pattern("a %%m b", 'm'), 0);
+    fn substitute_query_params() {
+        let url =
+            url::Url::parse("mailto:a@example.com?subject=Hello%20there&body=a+b%0Ac").unwrap();
+        let path = PathBuf::from(url.as_str());
+        let subst = Substitutions {
+            input: None,
+            url: Some(&url),
+            ..test_subst(&path, None)
+        };
+        assert_eq!(
+            HandlerMapping::build_cmd(
+                "mail -s %{query:subject} %{path} --body=%{query:body} %{query:cc} %{query:unclosed",
+                false,
+                &subst
+            )
+            .unwrap(),
+            vec![
+                "mail",
+                "-s",
+                "Hello there",
+                "a@example.com",
+                "--body=a b\nc",
+                "",
+                "%{query:unclosed"
+            ]
+        );
+        assert_eq!(
+            HandlerMapping::build_cmd(
+                "mail -s %{query:subject} '%{query:subject}' %{path} %{query:cc}",
+                true,
+                &subst
+            )
+            .unwrap(),
+            vec![
+                "sh",
+                "-c",
+                "mail -s \"${1}\" ''\"${1}\"'' \"${2}\" \"${3}\"",
+                "rsop",
+                "Hello there",
+                "a@example.com",
+                ""
+            ]
+        );
     }
 
     #[test]
-    fn count_pattern_mixed() {
-        assert_eq!(HandlerMapping::count_pattern("a %i b %%i c %i", 'i'), 2);
+    fn build_cmd_missing_url_parts() {
+        let url = url::Url::parse("ssh://example.com").unwrap();
+        let path = PathBuf::from(url.as_str());
+        let subst = Substitutions {
+            input: None,
+            url: Some(&url),
+            ..test_subst(&path, None)
+        };
+        assert_eq!(
+            HandlerMapping::build_cmd("ssh -p %{port} %{host}", false, &subst).unwrap(),
+            vec!["ssh", "-p", "", "example.com"]
+        );
+        let cmd =
+            HandlerMapping::build_cmd("printf '[%s]' -p %{port} %{host}", true, &subst).unwrap();
+        let output = Command::new(&cmd[0]).args(&cmd[1..]).output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "[-p][][example.com]"
+        );
     }
 
     #[test]
-    fn has_pattern_filter() {
-        let filter = FileFilter {
-            command: "gzip -dc %i".to_owned(),
-            shell: false,
-            no_pipe: false,
-            stdin_arg: None,
-        };
-        let processor = FileProcessor::Filter(filter);
-        assert!(processor.has_pattern('i'));
-        assert!(!processor.has_pattern('m'));
+    fn build_cmd_shell_empty_value() {
+        let subst = test_subst(Path::new(""), None);
+        assert_eq!(
+            HandlerMapping::build_cmd("hexyl %i | head", true, &subst).unwrap(),
+            vec!["sh", "-c", "hexyl  | head", "rsop"]
+        );
     }
 
     #[test]
-    fn build_cmd_no_shell() {
-        let cmd = HandlerMapping::build_cmd("cat /tmp/test.txt", false).unwrap();
-        assert_eq!(cmd, vec!["cat", "/tmp/test.txt"]);
+    fn terminal_cmd() {
+        let subst = test_subst(Path::new("/tmp/a b.txt"), None);
+        let cmd_args = HandlerMapping::build_cmd("less %i", false, &subst).unwrap();
+        assert_eq!(
+            HandlerMapping::terminal_cmd("foot --title %{filename} -e", cmd_args, &subst).unwrap(),
+            vec!["foot", "--title", "a b.txt", "-e", "less", "/tmp/a b.txt"]
+        );
     }
 
     #[test]
-    fn build_cmd_shell() {
-        let cmd = HandlerMapping::build_cmd("echo hello | grep h", true).unwrap();
-        assert_eq!(cmd, vec!["sh", "-c", "echo hello | grep h"]);
+    fn build_cmd_shell_runs() {
+        let subst = test_subst(Path::new("a \"b\" $(c) 'd'"), None);
+        let cmd = HandlerMapping::build_cmd("printf '%s|' %i \"%i\" '%i'", true, &subst).unwrap();
+        let output = Command::new(&cmd[0]).args(&cmd[1..]).output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "a \"b\" $(c) 'd'|a \"b\" $(c) 'd'|a \"b\" $(c) 'd'|"
+        );
     }
 
     #[test]
-    fn build_cmd_quoted() {
-        let cmd = HandlerMapping::build_cmd("cat '/tmp/my file.txt'", false).unwrap();
-        assert_eq!(cmd, vec!["cat", "/tmp/my file.txt"]);
+    fn build_cmd_shell_many_params() {
+        let url =
+            url::Url::parse("https://example.com/?a=A&b=B&c=C&d=D&e=E&f=F&g=G&h=H&i=I&j=J&k=K")
+                .unwrap();
+        let path = PathBuf::from(url.as_str());
+        let subst = Substitutions {
+            input: None,
+            url: Some(&url),
+            ..test_subst(&path, None)
+        };
+        let cmd = HandlerMapping::build_cmd(
+            "printf '[%s]' %{query:a} %{query:b} %{query:c} %{query:d} %{query:e} %{query:f} %{query:g} %{query:h} %{query:i} \"%{query:j}\" '%{query:k}' %{query:a}",
+            true,
+            &subst,
+        )
+        .unwrap();
+        let output = Command::new(&cmd[0]).args(&cmd[1..]).output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "[A][B][C][D][E][F][G][H][I][J][K][A]"
+        );
     }
 
     #[test]
-    fn build_cmd_invalid() {
-        let result = HandlerMapping::build_cmd("cat 'unclosed", false);
-        assert!(result.is_err());
+    fn build_cmd_shell_nested() {
+        let subst = test_subst(Path::new("/tmp/a  b"), None);
+        // Values are expanded as is in double quotes, so a nested shell splits them unless quoted again
+        let cmd =
+            HandlerMapping::build_cmd("sh -c \"echo \\\"%i\\\"; echo %i\"", true, &subst).unwrap();
+        assert_eq!(cmd[2], "sh -c \"echo \\\"${1}\\\"; echo ${1}\"");
+        let output = Command::new(&cmd[0]).args(&cmd[1..]).output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "/tmp/a  b\n/tmp/a b\n"
+        );
     }
 
     fn default_handler(command: &str) -> FileHandler {
@@ -1331,6 +3292,10 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         }
     }
 
@@ -1344,6 +3309,12 @@ mod tests {
             handler_edit: HashMap::new(),
             filter: HashMap::new(),
             handler_scheme: HashMap::new(),
+            default_handler_scheme: None,
+            terminal_command: None,
+            log: config::LogConfig::default(),
+            trusted_project_dirs: Vec::new(),
+            sources: Vec::new(),
+            origins: HashMap::new(),
         }
     }
 
@@ -1405,6 +3376,10 @@ mod tests {
                 shell: false,
                 no_pipe: true,
                 stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             },
         );
         assert!(HandlerMapping::new(&config).is_ok());
@@ -1429,6 +3404,10 @@ mod tests {
                 shell: false,
                 no_pipe: true,
                 stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             },
         );
         let err = HandlerMapping::new(&config).unwrap_err();
@@ -1454,6 +3433,10 @@ mod tests {
                 shell: false,
                 no_pipe: true,
                 stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             },
         );
         let err = HandlerMapping::new(&config).unwrap_err();
@@ -1479,6 +3462,10 @@ mod tests {
                 shell: false,
                 no_pipe: false,
                 stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             },
         );
         let err = HandlerMapping::new(&config).unwrap_err();
@@ -1504,6 +3491,10 @@ mod tests {
                 shell: false,
                 no_pipe: true,
                 stdin_arg: None,
+                requires: None,
+                terminal: false,
+                log_file: None,
+                process: ProcessOptions::default(),
             },
         );
         assert!(HandlerMapping::new(&config).is_ok());
@@ -1526,6 +3517,7 @@ mod tests {
                 shell: false,
                 no_pipe: false,
                 stdin_arg: None,
+                process: ProcessOptions::default(),
             },
         );
         let err = HandlerMapping::new(&config).unwrap_err();
@@ -1550,11 +3542,239 @@ mod tests {
                 shell: false,
                 no_pipe: true,
                 stdin_arg: None,
+                process: ProcessOptions::default(),
+            },
+        );
+        assert!(HandlerMapping::new(&config).is_ok());
+    }
+
+    #[test]
+    fn resolve_requirements() {
+        let mut config = minimal_config();
+        config.filetype.insert(
+            "image".to_owned(),
+            config::Filetype {
+                extensions: vec!["png".to_owned()],
+                mimes: vec![],
+            },
+        );
+        config.filetype.insert(
+            "any_image".to_owned(),
+            config::Filetype {
+                extensions: vec![],
+                mimes: vec!["image".to_owned()],
+            },
+        );
+        config.handler_open.insert(
+            "image".to_owned(),
+            FileHandler {
+                requires: Some(Requirement::Gui),
+                ..default_handler("imv %i")
+            },
+        );
+        config.handler_edit.insert(
+            "image".to_owned(),
+            FileHandler {
+                requires: Some(Requirement::Tty),
+                ..default_handler("gimp-tui %i")
+            },
+        );
+        config
+            .handler_open
+            .insert("any_image".to_owned(), default_handler("chafa %i"));
+        let mapping = HandlerMapping::new(&config).unwrap();
+        let extensions = ["png".to_owned()];
+        let resolve = |gui, tty| {
+            mapping
+                .resolve(
+                    &RsopMode::Open,
+                    &extensions,
+                    Some("image/png"),
+                    Capabilities { gui, tty },
+                )
+                .command
+        };
+
+        assert_eq!(resolve(true, true), "imv %i");
+        assert_eq!(resolve(true, false), "imv %i");
+        assert_eq!(resolve(false, true), "gimp-tui %i");
+        assert_eq!(resolve(false, false), "chafa %i");
+    }
+
+    #[test]
+    fn handler_mapping_terminal_without_terminal_command() {
+        let mut config = minimal_config();
+        config.handler_open.insert(
+            "text".to_owned(),
+            FileHandler {
+                terminal: true,
+                ..default_handler("less %i")
             },
         );
+        // Handler is run as is, which works if rsop itself runs in a terminal
+        assert!(HandlerMapping::new(&config).is_ok());
+
+        config.terminal_command = Some("foot -e".to_owned());
         assert!(HandlerMapping::new(&config).is_ok());
     }
 
+    #[test]
+    fn resolve_sources() {
+        let mut config = minimal_config();
+        config.filetype.insert(
+            "text".to_owned(),
+            config::Filetype {
+                extensions: vec!["txt".to_owned()],
+                mimes: vec!["text".to_owned()],
+            },
+        );
+        config
+            .handler_preview
+            .insert("text".to_owned(), default_handler("head %i"));
+        let mapping = HandlerMapping::new(&config).unwrap();
+
+        let by_ext = mapping.resolve(
+            &RsopMode::Preview,
+            &["txt".to_owned()],
+            None,
+            Capabilities::ALL,
+        );
+        assert_eq!(by_ext.filetype.as_deref(), Some("text"));
+        assert_eq!(by_ext.command, "head %i");
+        assert_eq!(by_ext.source, MatchSource::Extension);
+
+        let by_mime = mapping.resolve(
+            &RsopMode::Preview,
+            &[],
+            Some("text/plain"),
+            Capabilities::ALL,
+        );
+        assert_eq!(by_mime.filetype.as_deref(), Some("text"));
+        assert_eq!(by_mime.source, MatchSource::Mime);
+
+        let fallback = mapping.resolve(
+            &RsopMode::Open,
+            &["txt".to_owned()],
+            Some("text/plain"),
+            Capabilities::ALL,
+        );
+        assert!(fallback.filetype.is_none());
+        assert_eq!(fallback.command, "cat %i");
+        assert_eq!(fallback.source, MatchSource::Default);
+    }
+
+    #[test]
+    fn paths_mimes_keeps_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for i in 0..32 {
+            let path = dir.path().join(format!("{i}"));
+            if i % 2 == 0 {
+                fs::write(&path, "text").unwrap();
+            } else {
+                fs::create_dir(&path).unwrap();
+            }
+            paths.push(path);
+        }
+        paths.push(dir.path().join("missing"));
+
+        let mimes = HandlerMapping::paths_mimes(&paths);
+        assert_eq!(mimes.len(), paths.len());
+        for (i, mime) in mimes.iter().take(32).enumerate() {
+            let expected = if i % 2 == 0 {
+                "text/plain"
+            } else {
+                "inode/directory"
+            };
+            assert_eq!(*mime.as_ref().unwrap(), Some(expected));
+        }
+        assert!(mimes.last().unwrap().is_err());
+        assert!(HandlerMapping::paths_mimes(&[]).is_empty());
+    }
+
+    #[test]
+    fn filetype_lookup() {
+        let mut config = minimal_config();
+        config.filetype.insert(
+            "markdown".to_owned(),
+            config::Filetype {
+                extensions: vec!["md".to_owned()],
+                mimes: vec![],
+            },
+        );
+        config
+            .handler_preview
+            .insert("markdown".to_owned(), default_handler("mdcat %i"));
+        config.filetype.insert(
+            "text".to_owned(),
+            config::Filetype {
+                extensions: vec![],
+                mimes: vec!["text".to_owned()],
+            },
+        );
+        config
+            .handler_open
+            .insert("text".to_owned(), default_handler("less %i"));
+        let mapping = HandlerMapping::new(&config).unwrap();
+
+        // Extension wins over MIME, even if only bound to a preview handler
+        assert_eq!(
+            mapping.filetype(&["md".to_owned()], Some("text/plain")),
+            Some("markdown")
+        );
+        assert_eq!(
+            mapping.filetype(&["txt".to_owned()], Some("text/plain")),
+            Some("text")
+        );
+        assert_eq!(mapping.filetype(&[], Some("image/png")), None);
+        assert_eq!(mapping.filetype(&[], None), None);
+    }
+
+    #[test]
+    fn identify_json() {
+        let mut config = minimal_config();
+        config.filetype.insert(
+            "gz".to_owned(),
+            config::Filetype {
+                extensions: vec![],
+                mimes: vec!["application/gzip".to_owned()],
+            },
+        );
+        config.filter.insert(
+            "gz".to_owned(),
+            FileFilter {
+                command: "zcat %i".to_owned(),
+                shell: false,
+                no_pipe: false,
+                stdin_arg: None,
+                process: ProcessOptions::default(),
+            },
+        );
+        let mapping = HandlerMapping::new(&config).unwrap();
+
+        let ident = mapping.identify(
+            Some(Path::new("/tmp/a.gz")),
+            vec!["gz".to_owned()],
+            Some("application/gzip"),
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&ident).unwrap()).unwrap();
+        assert_eq!(json["path"], "/tmp/a.gz");
+        assert_eq!(json["mime"], "application/gzip");
+        assert_eq!(json["filetype"], "gz");
+        assert_eq!(
+            json["mime_hierarchy"],
+            serde_json::json!(["application/gzip", "application"])
+        );
+        assert_eq!(json["extensions"], serde_json::json!(["gz"]));
+        assert_eq!(json["preview"]["filetype"], "gz");
+        assert_eq!(json["preview"]["filter"], true);
+        assert_eq!(json["preview"]["source"], "mime");
+        // Edit falls back to open handlers
+        assert_eq!(json["edit"]["filetype"], "gz");
+        assert_eq!(json["edit"]["source"], "mime");
+    }
+
     #[test]
     fn pipe_forward_empty_header() {
         let mut src = io::Cursor::new(b"hello world");
@@ -1590,7 +3810,7 @@ mod tests {
         let pipe = io::Cursor::new(b"rest of data");
         let tmp = HandlerMapping::pipe_to_tmpfile(b"header-", pipe).unwrap();
 
-        let content = std::fs::read_to_string(tmp.path()).unwrap();
+        let content = fs::read_to_string(tmp.path()).unwrap();
         assert_eq!(content, "header-rest of data");
     }
 
@@ -1599,7 +3819,7 @@ mod tests {
         let pipe = io::Cursor::new(b"");
         let tmp = HandlerMapping::pipe_to_tmpfile(b"", pipe).unwrap();
 
-        let content = std::fs::read_to_string(tmp.path()).unwrap();
+        let content = fs::read_to_string(tmp.path()).unwrap();
         assert_eq!(content, "");
     }
 
@@ -1611,6 +3831,10 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
         let handlers = FileHandlers::new(&default);
         assert!(handlers.extensions.is_empty());
@@ -1626,6 +3850,10 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
         let mut handlers = FileHandlers::new(&default);
 
@@ -1635,29 +3863,40 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         }));
 
         let filetype = config::Filetype {
             extensions: vec!["txt".to_owned(), "log".to_owned()],
             mimes: vec!["text/plain".to_owned()],
         };
-        handlers.add(&processor, &filetype);
+        handlers.add("text", &processor, &filetype);
 
         assert!(handlers.extensions.contains_key("txt"));
         assert!(handlers.extensions.contains_key("log"));
         assert!(handlers.mimes.contains_key("text/plain"));
+        assert_eq!(handlers.mimes.get("text/plain").unwrap().filetype, "text");
     }
 
     #[test]
     fn scheme_handlers_new_and_add() {
-        let mut scheme_handlers = SchemeHandlers::new();
+        let mut scheme_handlers = SchemeHandlers::new(None, "").unwrap();
         assert!(scheme_handlers.schemes.is_empty());
 
         let handler = SchemeHandler {
             command: "firefox %i".to_owned(),
             shell: false,
+            log_file: None,
+            fetch: false,
+            process: ProcessOptions::default(),
+            rules: Vec::new(),
         };
-        scheme_handlers.add(&handler, "https");
+        scheme_handlers
+            .add(&handler, "https", "handler_scheme.https")
+            .unwrap();
         assert!(scheme_handlers.schemes.contains_key("https"));
         assert_eq!(
             scheme_handlers.schemes.get("https").unwrap().command,
@@ -1665,6 +3904,199 @@ mod tests {
         );
     }
 
+    #[test]
+    fn scheme_handlers_default() {
+        let handler = |command: &str| SchemeHandler {
+            command: command.to_owned(),
+            shell: false,
+            log_file: None,
+            fetch: false,
+            process: ProcessOptions::default(),
+            rules: Vec::new(),
+        };
+        let mut scheme_handlers = SchemeHandlers::new(None, "").unwrap();
+        scheme_handlers
+            .add(&handler("firefox %i"), "https", "handler_scheme.https")
+            .unwrap();
+        assert!(scheme_handlers.get("gopher").is_none());
+
+        let default = handler("echo %i");
+        let mut default_handlers =
+            SchemeHandlers::new(Some(&default), "default_handler_scheme").unwrap();
+        default_handlers
+            .add(&handler("firefox %i"), "https", "handler_scheme.https")
+            .unwrap();
+        assert_eq!(default_handlers.get("https").unwrap().command, "firefox %i");
+        assert_eq!(default_handlers.get("gopher").unwrap().command, "echo %i");
+    }
+
+    #[test]
+    fn local_file_path() {
+        assert_eq!(
+            HandlerMapping::local_file_path(&url::Url::parse("file:///tmp/a%20b's.txt").unwrap()),
+            Some(PathBuf::from("/tmp/a b's.txt"))
+        );
+        assert_eq!(
+            HandlerMapping::local_file_path(&url::Url::parse("file://LocalHost/tmp/a").unwrap()),
+            Some(PathBuf::from("/tmp/a"))
+        );
+        let hostname = nix::unistd::gethostname().unwrap();
+        let url = format!("file://{}/tmp/a", hostname.to_str().unwrap());
+        assert_eq!(
+            HandlerMapping::local_file_path(&url::Url::parse(&url).unwrap()),
+            Some(PathBuf::from("/tmp/a"))
+        );
+        assert_eq!(
+            HandlerMapping::local_file_path(
+                &url::Url::parse("file://nonexistent.example.com/tmp/a").unwrap()
+            ),
+            None
+        );
+    }
+
+    /// Serve one response per connection on a local port, to stand in for a HTTP server
+    fn http_server(responses: Vec<&'static str>) -> (String, thread::JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/doc", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = io::BufReader::new(stream);
+                let mut line = String::new();
+                while io::BufRead::read_line(&mut reader, &mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn env_mime_by_extension() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("a.txt");
+        fs::write(&path, "hello\n").unwrap();
+        let out_path = tmp_dir.path().join("out");
+        let mut config = minimal_config();
+        config.filetype.insert(
+            "text".to_owned(),
+            config::Filetype {
+                extensions: vec!["txt".to_owned()],
+                mimes: Vec::new(),
+            },
+        );
+        let mut handler =
+            default_handler(&format!("echo \"$RSOP_MIME\" > '{}'", out_path.display()));
+        handler.shell = true;
+        config.handler_open.insert("text".to_owned(), handler);
+        let mapping = HandlerMapping::new(&config).unwrap();
+
+        // Matched by extension, with no %m in the command, but the MIME type is still probed for the environment
+        mapping.handle_path(&RsopMode::Open, &path).unwrap();
+        assert_eq!(fs::read_to_string(&out_path).unwrap(), "text/plain\n");
+    }
+
+    /// Mapping with a PDF handler writing to `out.pdf`, a default open handler writing to `default`, and a fetch
+    /// handler for the `http` scheme
+    fn fetch_mapping(dir: &Path, fetch_command: &str) -> HandlerMapping {
+        let mut config = minimal_config();
+        config.filetype.insert(
+            "pdf".to_owned(),
+            config::Filetype {
+                extensions: Vec::new(),
+                mimes: vec!["application/pdf".to_owned()],
+            },
+        );
+        let mut handler = default_handler(&format!("cat > '{}'", dir.join("out.pdf").display()));
+        handler.shell = true;
+        config.handler_open.insert("pdf".to_owned(), handler);
+        config.default_handler_open =
+            default_handler(&format!("touch '{}'", dir.join("default").display()));
+        config.default_handler_open.shell = true;
+        let mut fetch_handler: SchemeHandler =
+            toml::from_str("command = \"\"\nfetch = true").unwrap();
+        fetch_handler.command = fetch_command.to_owned();
+        config
+            .handler_scheme
+            .insert("http".to_owned(), fetch_handler);
+        HandlerMapping::new(&config).unwrap()
+    }
+
+    #[test]
+    fn fetch_url() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let body = "%PDF-1.4\n%%EOF\n";
+        let doc_path = tmp_dir.path().join("doc");
+        fs::write(&doc_path, body).unwrap();
+        let url = Path::new("http://example.com/doc");
+
+        let mapping = fetch_mapping(tmp_dir.path(), &format!("cat '{}'", doc_path.display()));
+        mapping.handle_path(&RsopMode::XdgOpen, url).unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("out.pdf")).unwrap(),
+            body
+        );
+
+        // A failed fetch is reported without running any handler on its empty output
+        let failing_mapping = fetch_mapping(tmp_dir.path(), "sh -c 'exit 6'");
+        let err = failing_mapping
+            .handle_path(&RsopMode::XdgOpen, url)
+            .unwrap_err();
+        assert!(matches!(err, HandlerError::Exit { .. }), "{err}");
+        assert!(!tmp_dir.path().join("default").exists());
+    }
+
+    #[test]
+    fn fetch_url_http() {
+        if Command::new("curl").arg("--version").output().is_err() {
+            eprintln!("curl is not available, skipping test");
+            return;
+        }
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mapping = fetch_mapping(tmp_dir.path(), "curl -sSf %i");
+        let (url, server) = http_server(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\n%PDF-1.4\n%%EOF\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+
+        mapping
+            .handle_path(&RsopMode::XdgOpen, Path::new(&url))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("out.pdf")).unwrap(),
+            "%PDF-1.4\n%%EOF\n"
+        );
+
+        let err = mapping
+            .handle_path(&RsopMode::XdgOpen, Path::new(&url))
+            .unwrap_err();
+        assert!(matches!(err, HandlerError::Exit { .. }), "{err}");
+        assert!(!tmp_dir.path().join("default").exists());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn scheme_rules_nested() {
+        let mut config = minimal_config();
+        let handler: SchemeHandler = toml::from_str(
+            r#"
+command = "firefox %i"
+
+[[rules]]
+host = "example.com"
+command = "firefox -P work %i"
+
+[[rules.rules]]
+command = "mpv %i"
+"#,
+        )
+        .unwrap();
+        config.handler_scheme.insert("https".to_owned(), handler);
+        let err = HandlerMapping::new(&config).unwrap_err();
+        assert!(err.to_string().contains("can not be nested"));
+    }
+
     #[test]
     fn validate_handler_ok() {
         let handler = FileHandler {
@@ -1673,8 +4105,12 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_ok());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_ok());
     }
 
     #[test]
@@ -1686,8 +4122,12 @@ mod tests {
             shell: false,
             no_pipe: true,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_ok());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_ok());
     }
 
     #[test]
@@ -1699,8 +4139,12 @@ mod tests {
             shell: false,
             no_pipe: true,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_err());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_err());
     }
 
     #[test]
@@ -1712,8 +4156,12 @@ mod tests {
             shell: false,
             no_pipe: true,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_err());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_err());
     }
 
     #[test]
@@ -1722,7 +4170,7 @@ mod tests {
         let pipe = io::Cursor::new(b"rest of data");
         let file = HandlerMapping::pipe_to_memfd(b"header-", pipe).unwrap();
         let path = format!("/proc/self/fd/{}", file.as_raw_fd());
-        let content = std::fs::read_to_string(&path).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
         assert_eq!(content, "header-rest of data");
     }
 
@@ -1734,8 +4182,12 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_err());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_err());
     }
 
     #[test]
@@ -1746,8 +4198,12 @@ mod tests {
             shell: false,
             no_pipe: true,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_ok());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_ok());
     }
 
     #[test]
@@ -1758,7 +4214,11 @@ mod tests {
             shell: false,
             no_pipe: false,
             stdin_arg: None,
+            requires: None,
+            terminal: false,
+            log_file: None,
+            process: ProcessOptions::default(),
         };
-        assert!(HandlerMapping::validate_handler(&handler).is_ok());
+        assert!(HandlerMapping::validate_handler(&handler, "handler").is_ok());
     }
 }
@@ -0,0 +1,400 @@
+//! Log output to stderr, a file or the systemd journal, and activity log of handler runs
+//!
+//! The logger is set up early to stderr, and reconfigured with command line options, and then once the config has been
+//! read.
+//! The activity log is a JSON lines file, with an entry for each handler run.
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, Write as _},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context as _;
+
+use crate::config::{LogConfig, LogDestination};
+
+/// Name of the log file in the state directory, for the file destination
+const LOG_FILENAME: &str = "rsop.log";
+
+/// Name of the activity log file in the state directory
+const ACTIVITY_LOG_FILENAME: &str = "activity.jsonl";
+
+/// Socket of the systemd journal native protocol
+const JOURNAL_SOCKET_PATH: &str = "/run/systemd/journal/socket";
+
+/// Where log messages are currently written
+enum Sink {
+    Stderr(simple_logger::SimpleLogger),
+    File(File),
+    Journal(UnixDatagram),
+}
+
+struct Logger {
+    sink: Mutex<Sink>,
+}
+
+static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger {
+    sink: Mutex::new(Sink::Stderr(simple_logger::SimpleLogger::new())),
+});
+
+static ACTIVITY_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+static OVERRIDES: Mutex<Overrides> = Mutex::new(Overrides {
+    verbose: 0,
+    quiet: 0,
+    file: None,
+    quiet_stderr: false,
+});
+
+/// Log settings from the command line, taking precedence over config
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Overrides {
+    /// How many levels to raise the maximum level by
+    pub verbose: u8,
+    /// How many levels to lower the maximum level by
+    pub quiet: u8,
+    /// Log file, replacing the configured destination
+    pub file: Option<PathBuf>,
+    /// Only log errors to stderr unless a level is set, because it may end up in a preview pane
+    pub quiet_stderr: bool,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // There is nowhere to report failures to log, so ignore them
+        let Ok(mut sink) = self.sink.lock() else {
+            return;
+        };
+        match &mut *sink {
+            Sink::Stderr(logger) => logger.log(record),
+            Sink::File(file) => {
+                let _ = file.write_all(file_line(record, unix_time()).as_bytes());
+            }
+            Sink::Journal(socket) => {
+                let _ = socket.send(&journal_entry(record));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = match &mut *sink {
+                Sink::Stderr(_) => io::stderr().flush(),
+                Sink::File(file) => file.flush(),
+                Sink::Journal(_) => Ok(()),
+            };
+        }
+    }
+}
+
+/// Current Unix timestamp, in seconds
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Set up logging to stderr, until `set_overrides` or `configure` is called
+pub(crate) fn init() -> anyhow::Result<()> {
+    log::set_logger(&*LOGGER).context("Failed to init logger")?;
+    log::set_max_level(log::LevelFilter::Info);
+    Ok(())
+}
+
+/// Apply log settings from the command line, now and when `configure` is called later
+pub(crate) fn set_overrides(overrides: Overrides) -> anyhow::Result<()> {
+    *OVERRIDES
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Log overrides lock poisoned"))? = overrides;
+    configure(&LogConfig::default())
+}
+
+/// Apply log settings from config
+pub(crate) fn configure(cfg: &LogConfig) -> anyhow::Result<()> {
+    let overrides = OVERRIDES
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Log overrides lock poisoned"))?
+        .clone();
+    log::set_max_level(max_level(cfg, &overrides));
+
+    let sink = match (cfg.destination, &overrides.file) {
+        (_, Some(path)) => Sink::File(open_append(path)?),
+        (LogDestination::Stderr, None) => Sink::Stderr(simple_logger::SimpleLogger::new()),
+        (LogDestination::File, None) => {
+            let path = state_file(cfg.file.as_deref(), LOG_FILENAME)?;
+            Sink::File(open_append(&path)?)
+        }
+        (LogDestination::Journal, None) => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNAL_SOCKET_PATH).with_context(|| {
+                format!("Failed to connect to journal socket {JOURNAL_SOCKET_PATH:?}")
+            })?;
+            Sink::Journal(socket)
+        }
+    };
+    *LOGGER
+        .sink
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Logger lock poisoned"))? = sink;
+
+    let activity_log = if cfg.activity {
+        let path = state_file(cfg.activity_file.as_deref(), ACTIVITY_LOG_FILENAME)?;
+        log::debug!("Recording activity to {path:?}");
+        Some(open_append(&path)?)
+    } else {
+        None
+    };
+    *ACTIVITY_LOG
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Activity log lock poisoned"))? = activity_log;
+
+    Ok(())
+}
+
+/// Get maximum log level from config level or default, shifted by command line verbosity
+fn max_level(cfg: &LogConfig, overrides: &Overrides) -> log::LevelFilter {
+    let to_stderr = (cfg.destination == LogDestination::Stderr) && overrides.file.is_none();
+    let default = if overrides.quiet_stderr && to_stderr {
+        log::LevelFilter::Error
+    } else {
+        log::LevelFilter::Info
+    };
+    let level = cfg.level.unwrap_or(default);
+    let levels: Vec<_> = log::LevelFilter::iter().collect();
+    let index = levels
+        .iter()
+        .position(|l| *l == level)
+        .unwrap_or_default()
+        .saturating_add(usize::from(overrides.verbose))
+        .saturating_sub(usize::from(overrides.quiet))
+        .min(levels.len() - 1);
+    levels[index]
+}
+
+/// Get path of a log file, from config or in the state directory
+fn state_file(path: Option<&Path>, filename: &str) -> anyhow::Result<PathBuf> {
+    path.map_or_else(
+        || {
+            xdg::BaseDirectories::with_prefix(env!("CARGO_PKG_NAME"))
+                .place_state_file(filename)
+                .context("Failed to create state directory")
+        },
+        |p| Ok(p.to_owned()),
+    )
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?}"))
+}
+
+/// Format a log record as a log file line
+fn file_line(record: &log::Record, ts: u64) -> String {
+    format!(
+        "[{ts}] {:<5} [{}] {}\n",
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// Build a journal entry for a log record, see <https://systemd.io/JOURNAL_NATIVE_PROTOCOL/>
+fn journal_entry(record: &log::Record) -> Vec<u8> {
+    let priority = match record.level() {
+        log::Level::Error => "3",
+        log::Level::Warn => "4",
+        log::Level::Info => "6",
+        log::Level::Debug | log::Level::Trace => "7",
+    };
+    let mut entry = Vec::new();
+    journal_field(&mut entry, "PRIORITY", priority);
+    journal_field(&mut entry, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+    journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    journal_field(&mut entry, "CODE_MODULE", record.target());
+    if let Some(file) = record.file() {
+        journal_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        journal_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        // Multi line values need to be length prefixed
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Handler run, as recorded in the activity log
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Activity<'a> {
+    /// Unix timestamp, in seconds
+    pub time: u64,
+    pub mode: String,
+    /// Path or URL, if the handler did not read piped data
+    pub target: Option<Cow<'a, str>>,
+    pub mime: Option<&'a str>,
+    pub filetype: Option<&'a str>,
+    pub argv: &'a [String],
+    /// Run duration, if the handler was waited for
+    pub duration_ms: Option<u128>,
+    /// Exit code, if the handler was waited for and exited normally
+    pub exit_code: Option<i32>,
+}
+
+/// Record a handler run in the activity log, if enabled
+pub(crate) fn record_activity(activity: &Activity) {
+    let Ok(mut activity_log) = ACTIVITY_LOG.lock() else {
+        return;
+    };
+    if let Some(file) = activity_log.as_mut() {
+        if let Err(err) = write_activity(file, activity) {
+            log::warn!("Failed to write activity log: {err:#}");
+        }
+    }
+}
+
+fn write_activity<W>(writer: &mut W, activity: &Activity) -> anyhow::Result<()>
+where
+    W: io::Write,
+{
+    let mut line = serde_json::to_vec(activity)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_level_overrides() {
+        let mut cfg = LogConfig::default();
+        let mut overrides = Overrides::default();
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Info);
+        overrides.verbose = 1;
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Debug);
+        overrides.verbose = 5;
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Trace);
+        overrides.verbose = 0;
+        overrides.quiet = 2;
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Error);
+        overrides.quiet = 5;
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Off);
+
+        cfg.level = Some(log::LevelFilter::Warn);
+        overrides.quiet = 1;
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Error);
+    }
+
+    #[test]
+    fn max_level_quiet_stderr() {
+        let mut cfg = LogConfig::default();
+        let mut overrides = Overrides {
+            quiet_stderr: true,
+            ..Overrides::default()
+        };
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Error);
+        overrides.verbose = 1;
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Warn);
+
+        overrides.verbose = 0;
+        overrides.file = Some(PathBuf::from("/tmp/rsop.log"));
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Info);
+
+        overrides.file = None;
+        cfg.level = Some(log::LevelFilter::Debug);
+        assert_eq!(max_level(&cfg, &overrides), log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn file_line_format() {
+        let line = file_line(
+            &log::Record::builder()
+                .args(format_args!("hello {}", 42))
+                .level(log::Level::Warn)
+                .target("rsop::handler")
+                .build(),
+            1_700_000_000,
+        );
+        assert_eq!(line, "[1700000000] WARN  [rsop::handler] hello 42\n");
+    }
+
+    #[test]
+    fn journal_entry_fields() {
+        let entry = journal_entry(
+            &log::Record::builder()
+                .args(format_args!("first\nsecond"))
+                .level(log::Level::Info)
+                .target("rsop")
+                .line(Some(12))
+                .build(),
+        );
+
+        let mut expected = b"PRIORITY=6\nSYSLOG_IDENTIFIER=rsop\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&12_u64.to_le_bytes());
+        expected.extend_from_slice(b"first\nsecond\nCODE_MODULE=rsop\nCODE_LINE=12\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn activity_line() {
+        let argv = vec!["less".to_owned(), "/tmp/a.txt".to_owned()];
+        let activity = Activity {
+            time: 1_700_000_000,
+            mode: "open".to_owned(),
+            target: Some(Cow::Borrowed("/tmp/a.txt")),
+            mime: Some("text/plain"),
+            filetype: Some("text"),
+            argv: &argv,
+            duration_ms: Some(1500),
+            exit_code: Some(0),
+        };
+        let mut buf = Vec::new();
+        write_activity(&mut buf, &activity).unwrap();
+        write_activity(&mut buf, &activity).unwrap();
+
+        let buf = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = buf.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "time": 1_700_000_000,
+                "mode": "open",
+                "target": "/tmp/a.txt",
+                "mime": "text/plain",
+                "filetype": "text",
+                "argv": ["less", "/tmp/a.txt"],
+                "duration_ms": 1500,
+                "exit_code": 0,
+            })
+        );
+    }
+}
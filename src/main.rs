@@ -1,6 +1,16 @@
 //! RSOP
 
-use std::{collections::BTreeMap, env, path::Path, str::FromStr as _, sync::LazyLock};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsStr,
+    io::{self, Read as _},
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr as _,
+    sync::LazyLock,
+};
 
 use anyhow::Context as _;
 use clap::Parser as _;
@@ -8,7 +18,12 @@ use strum::VariantNames as _;
 
 mod cli;
 mod config;
+#[cfg(target_os = "linux")]
+mod daemon;
 mod handler;
+mod logger;
+mod stderr_log;
+mod xdg_open;
 
 #[derive(
     Clone, Debug, Default, Eq, PartialEq, strum::Display, strum::EnumString, strum::VariantNames,
@@ -34,11 +49,13 @@ static BIN_NAME_TO_MODE: LazyLock<BTreeMap<&'static str, RsopMode>> = LazyLock::
     ])
 });
 
-fn runtime_mode() -> anyhow::Result<RsopMode> {
+/// Get mode from binary name or environment, or `None` if ambiguous
+fn runtime_mode() -> anyhow::Result<Option<RsopMode>> {
     // Get from env var
     let env_mode = env::var("RSOP_MODE");
     if let Ok(env_mode) = env_mode {
         return RsopMode::from_str(&env_mode)
+            .map(Some)
             .with_context(|| format!("Unexpected value for RSOP_MODE: {env_mode:?}"));
     }
 
@@ -52,10 +69,14 @@ fn runtime_mode() -> anyhow::Result<RsopMode> {
         .ok_or_else(|| anyhow::anyhow!("Unable to get current binary filename"))?;
     if let Some(bin_name) = bin_name {
         if let Some(mode) = BIN_NAME_TO_MODE.get(bin_name) {
-            return Ok(mode.to_owned());
+            return Ok(Some(mode.to_owned()));
         }
     }
 
+    Ok(None)
+}
+
+fn warn_ambiguous_mode() {
     let mut sorted_variants = Vec::from(RsopMode::VARIANTS);
     sorted_variants.sort_unstable();
     log::warn!(
@@ -69,36 +90,148 @@ fn runtime_mode() -> anyhow::Result<RsopMode> {
             .join("/"),
         sorted_variants.join("/")
     );
-    Ok(RsopMode::default())
+}
+
+/// Read a list of paths from stdin, separated by a given byte
+fn read_stdin_paths(separator: u8) -> anyhow::Result<Vec<PathBuf>> {
+    let mut data = Vec::new();
+    io::stdin()
+        .read_to_end(&mut data)
+        .context("Failed to read paths from stdin")?;
+    Ok(data
+        .split(|b| *b == separator)
+        .filter(|p| !p.is_empty())
+        .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+        .collect())
+}
+
+/// Read paths from stdin in place of command line ones, if requested in identify mode
+pub(crate) fn read_paths(
+    mode: &RsopMode,
+    cl_opts: &mut cli::CommandLineOpts,
+) -> anyhow::Result<()> {
+    if (*mode == RsopMode::Identify) && (cl_opts.stdin0 || cl_opts.stdin_lines) {
+        cl_opts.paths = read_stdin_paths(if cl_opts.stdin0 { b'\0' } else { b'\n' })?;
+    }
+    Ok(())
+}
+
+/// Log settings from command line
+pub(crate) fn log_overrides(mode: &RsopMode, cl_opts: &cli::CommandLineOpts) -> logger::Overrides {
+    logger::Overrides {
+        verbose: cl_opts.verbose,
+        quiet: cl_opts.quiet,
+        file: cl_opts.log_file.clone(),
+        quiet_stderr: *mode == RsopMode::Preview,
+    }
+}
+
+/// Process input according to mode and command line
+pub(crate) fn run(
+    mode: &RsopMode,
+    cl_opts: &cli::CommandLineOpts,
+    handlers: &handler::HandlerMapping,
+) -> anyhow::Result<()> {
+    if *mode == RsopMode::Identify {
+        let format = if cl_opts.json {
+            handler::IdentifyFormat::Json
+        } else if cl_opts.filetype {
+            handler::IdentifyFormat::Filetype
+        } else {
+            handler::IdentifyFormat::Mime
+        };
+        if cl_opts.stdin0 || cl_opts.stdin_lines {
+            // Already read by read_paths
+            handlers.identify_paths(&cl_opts.paths, format)?;
+        } else {
+            match cl_opts.paths.as_slice() {
+                [] => handlers.identify_pipe(format)?,
+                [path] => handlers.identify_path(path, format)?,
+                paths => handlers.identify_paths(paths, format)?,
+            }
+        }
+    } else {
+        anyhow::ensure!(
+            !cl_opts.stdin0 && !cl_opts.stdin_lines && (cl_opts.paths.len() <= 1),
+            "Multiple paths are only supported in identify mode"
+        );
+        if let Some(path) = cl_opts.paths.first() {
+            handlers.handle_path(mode, path)?;
+        } else {
+            handlers.handle_pipe(mode)?;
+        }
+    }
+
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     // Init logger
-    simple_logger::SimpleLogger::new()
-        .init()
-        .context("Failed to init logger")?;
+    logger::init()?;
 
     // Parse command line opts
     let mode = runtime_mode()?;
+    let mut cl_opts = if mode == Some(RsopMode::XdgOpen) {
+        xdg_open::command_line_opts()
+    } else {
+        cli::CommandLineOpts::parse()
+    };
+    logger::set_overrides(log_overrides(
+        mode.as_ref().unwrap_or(&RsopMode::default()),
+        &cl_opts,
+    ))?;
+    let mode = mode.unwrap_or_else(|| {
+        warn_ambiguous_mode();
+        RsopMode::default()
+    });
     log::trace!("Runtime mode: {mode:?}");
-    let cl_opts = cli::CommandLineOpts::parse();
     log::trace!("{cl_opts:?}");
 
+    if cl_opts.init_config {
+        let path = config::init_config(cl_opts.config.as_deref(), cl_opts.advanced, cl_opts.force)?;
+        log::info!("Config written to {path:?}");
+        return Ok(());
+    }
+
+    // Let a running daemon do the job if we can
+    #[cfg(target_os = "linux")]
+    if !cl_opts.daemon && matches!(mode, RsopMode::Preview | RsopMode::Identify) {
+        if let Some(code) = daemon::forward(&mode, cl_opts.config.as_deref())
+            .context("Failed to forward to daemon")?
+        {
+            process::exit(code);
+        }
+    }
+
     // Parse config
-    let cfg = config::parse_config().context("Failed to read config")?;
+    let cfg = config::parse_config(cl_opts.config.as_deref()).context("Failed to read config")?;
+
+    // Apply project config, except for the daemon which does it for each request
+    let cfg = if cl_opts.daemon {
+        cfg
+    } else {
+        read_paths(&mode, &mut cl_opts)?;
+        cfg.with_project_config(&cl_opts.paths)?.unwrap_or(cfg)
+    };
+
+    logger::configure(&cfg.log).context("Failed to configure logging")?;
 
     // Build mapping for fast searches
     let handlers = handler::HandlerMapping::new(&cfg).context("Failed to build handler mapping")?;
     log::debug!("{handlers:?}");
 
-    // Do the job
-    if let Some(path) = cl_opts.path {
-        handlers.handle_path(&mode, &path)?;
-    } else {
-        handlers.handle_pipe(&mode)?;
+    if cl_opts.daemon {
+        #[cfg(target_os = "linux")]
+        return daemon::serve(cl_opts.config.as_deref(), cfg, handlers);
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("Daemon mode is only supported on Linux");
     }
 
-    Ok(())
+    // Do the job
+    if mode == RsopMode::XdgOpen {
+        process::exit(xdg_open::open(&cl_opts.paths, &handlers));
+    }
+    run(&mode, &cl_opts, &handlers)
 }
 
 #[cfg(test)]
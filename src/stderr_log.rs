@@ -0,0 +1,139 @@
+//! Capture of handler and filter stderr to a rotating log file in the XDG state directory
+//!
+//! Each capture starts with a header line with a timestamp and the command, followed by the command's stderr lines.
+//! When the log file grows above a size limit, it is renamed with a `.1` suffix, replacing the previous one.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufRead as _, BufReader, Read, Write as _},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::Context as _;
+
+use crate::logger;
+
+/// Name of the log file in the state directory
+const LOG_FILENAME: &str = "stderr.log";
+
+/// Size above which the log file is rotated
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// How many of the last stderr lines are kept to report errors
+const TAIL_LINE_COUNT: usize = 10;
+
+/// Open the log file for a command, rotating it first if needed
+pub(crate) fn open(cmd: &[String]) -> anyhow::Result<File> {
+    let path = xdg::BaseDirectories::with_prefix(env!("CARGO_PKG_NAME"))
+        .place_state_file(LOG_FILENAME)
+        .context("Failed to create state directory")?;
+    open_path(&path, cmd)
+}
+
+fn open_path(path: &Path, cmd: &[String]) -> anyhow::Result<File> {
+    if fs::metadata(path).is_ok_and(|m| m.len() > MAX_LOG_SIZE) {
+        let mut rotated_path = path.as_os_str().to_owned();
+        rotated_path.push(".1");
+        let rotated_path = PathBuf::from(rotated_path);
+        log::debug!("Rotating {path:?} to {rotated_path:?}");
+        fs::rename(path, &rotated_path)
+            .with_context(|| format!("Failed to rotate {path:?} to {rotated_path:?}"))?;
+    }
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    writeln!(file, "[{}] {cmd:?}", logger::unix_time())?;
+    log::debug!("Capturing stderr to {path:?}");
+    Ok(file)
+}
+
+/// Thread copying stderr of a child process to the log file, and optionally to rsop's stderr
+pub(crate) struct Capture {
+    thread: thread::JoinHandle<io::Result<VecDeque<String>>>,
+}
+
+impl Capture {
+    pub(crate) fn start<R>(src: R, mut log: File, echo: bool) -> Capture
+    where
+        R: Read + Send + 'static,
+    {
+        let thread = thread::spawn(move || {
+            let mut reader = BufReader::new(src);
+            let mut tail = VecDeque::with_capacity(TAIL_LINE_COUNT);
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line)? > 0 {
+                log.write_all(&line)?;
+                if echo {
+                    io::stderr().write_all(&line)?;
+                }
+                if tail.len() == TAIL_LINE_COUNT {
+                    tail.pop_front();
+                }
+                tail.push_back(String::from_utf8_lossy(&line).trim_end().to_owned());
+                line.clear();
+            }
+            Ok(tail)
+        });
+        Capture { thread }
+    }
+
+    /// Wait for the child's stderr to be closed, and return its last lines
+    pub(crate) fn finish(self) -> anyhow::Result<Vec<String>> {
+        let tail = self
+            .thread
+            .join()
+            .map_err(|e| anyhow::anyhow!("Stderr capture thread panicked: {e:?}"))?
+            .context("Failed to capture stderr")?;
+        Ok(tail.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_tail() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join(LOG_FILENAME);
+        let cmd = vec!["cmd".to_owned(), "arg".to_owned()];
+        let lines: Vec<_> = (1..=15).map(|i| format!("line {i}")).collect();
+        let input = lines.join("\n") + "\n";
+
+        let log = open_path(&path, &cmd).unwrap();
+        let tail = Capture::start(io::Cursor::new(input.clone()), log, false)
+            .finish()
+            .unwrap();
+
+        assert_eq!(tail, lines[5..]);
+        let content = fs::read_to_string(&path).unwrap();
+        let (header, logged) = content.split_once('\n').unwrap();
+        assert!(header.ends_with("] [\"cmd\", \"arg\"]"));
+        assert_eq!(logged, input);
+    }
+
+    #[test]
+    fn rotate() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join(LOG_FILENAME);
+        let rotated_path = tmp_dir.path().join(format!("{LOG_FILENAME}.1"));
+        let cmd = vec!["cmd".to_owned()];
+
+        fs::write(&path, vec![b'a'; 1024]).unwrap();
+        drop(open_path(&path, &cmd).unwrap());
+        assert!(!rotated_path.exists());
+
+        fs::write(
+            &path,
+            vec![b'a'; usize::try_from(MAX_LOG_SIZE).unwrap() + 1],
+        )
+        .unwrap();
+        drop(open_path(&path, &cmd).unwrap());
+        assert_eq!(fs::metadata(&rotated_path).unwrap().len(), MAX_LOG_SIZE + 1);
+        assert!(fs::metadata(&path).unwrap().len() < 100);
+    }
+}
@@ -0,0 +1,252 @@
+//! Command line and exit codes compatible with xdg-open, see <https://portland.freedesktop.org/doc/xdg-open.html>
+//!
+//! Unlike xdg-open, several files or URLs can be passed, they are opened in order.
+
+use std::{env, ffi::OsString, io, path::PathBuf, process};
+
+use clap::Parser as _;
+
+use crate::{
+    RsopMode, cli,
+    handler::{HandlerError, HandlerMapping},
+};
+
+/// Error in command line syntax
+const EXIT_SYNTAX: i32 = 1;
+/// One of the files passed on the command line did not exist
+const EXIT_FILE_MISSING: i32 = 2;
+/// A required tool could not be found
+const EXIT_TOOL_MISSING: i32 = 3;
+/// The action failed
+const EXIT_ACTION_FAILED: i32 = 4;
+
+const HELP: &str = "xdg-open - opens a file or URL in the user's preferred application
+
+Synopsis
+
+xdg-open { file | URL }...
+
+xdg-open { --help | --manual | --version }
+
+Use 'xdg-open --manual' for additional info.";
+
+const MANUAL: &str = "NAME
+    xdg-open - opens a file or URL in the user's preferred application
+
+SYNOPSIS
+    xdg-open { file | URL }...
+
+    xdg-open { --help | --manual | --version }
+
+DESCRIPTION
+    xdg-open opens a file or URL in the user's preferred application. If a URL is provided, it is opened with the
+    handler of its scheme. If a file is provided, it is opened with the handler for its type. Several files or URLs can
+    be provided, they are opened in order.
+
+    This implementation is provided by rsop, handlers are set up in its config file.
+
+OPTIONS
+    --help
+        Show command synopsis.
+
+    --manual
+        Show this manual page.
+
+    --version
+        Show the version.
+
+EXIT CODES
+    An exit code of 0 indicates success while a non-zero exit code indicates failure. The following failure codes can
+    be returned:
+
+    1   Error in command line syntax.
+    2   One of the files passed on the command line did not exist.
+    3   A required tool could not be found.
+    4   The action failed.
+
+    If several files or URLs are provided, the exit code is the one of the first failure.";
+
+/// Parsed xdg-open command line
+#[derive(Debug, Eq, PartialEq)]
+enum Args {
+    Help,
+    Manual,
+    Version,
+    Open(Vec<PathBuf>),
+}
+
+/// Parse xdg-open arguments, not including the binary path
+fn parse_args<I>(args: I) -> Result<Args, String>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let args: Vec<OsString> = args.into_iter().collect();
+    // Like xdg-open, these are accepted anywhere on the command line
+    for (option, parsed) in [
+        ("--help", Args::Help),
+        ("--manual", Args::Manual),
+        ("--version", Args::Version),
+    ] {
+        if args.iter().any(|a| a == option) {
+            return Ok(parsed);
+        }
+    }
+
+    let mut paths = Vec::with_capacity(args.len());
+    for arg in args {
+        if arg.as_encoded_bytes().starts_with(b"-") {
+            return Err(format!("unexpected option {:?}", arg.to_string_lossy()));
+        }
+        paths.push(PathBuf::from(arg));
+    }
+    if paths.is_empty() {
+        return Err("file or URL argument missing".to_owned());
+    }
+    Ok(Args::Open(paths))
+}
+
+/// Parse command line, and exit if there is nothing to open
+pub(crate) fn command_line_opts() -> cli::CommandLineOpts {
+    let mut args = env::args_os();
+    let bin = args.next().unwrap_or_else(|| OsString::from("xdg-open"));
+    match parse_args(args) {
+        Ok(Args::Help) => {
+            println!("{HELP}");
+            process::exit(0);
+        }
+        Ok(Args::Manual) => {
+            println!("{MANUAL}");
+            process::exit(0);
+        }
+        Ok(Args::Version) => {
+            println!(
+                "xdg-open ({}) {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            );
+            process::exit(0);
+        }
+        Ok(Args::Open(paths)) => {
+            // Still get config path from environment
+            let mut cl_opts = cli::CommandLineOpts::parse_from([bin]);
+            cl_opts.paths = paths;
+            cl_opts
+        }
+        Err(msg) => {
+            eprintln!("xdg-open: {msg}\nTry 'xdg-open --help' for more information.");
+            process::exit(EXIT_SYNTAX);
+        }
+    }
+}
+
+/// Get exit code for a handler error
+fn exit_code(err: &HandlerError) -> i32 {
+    match err {
+        HandlerError::Input { err, .. } if err.kind() == io::ErrorKind::NotFound => {
+            EXIT_FILE_MISSING
+        }
+        HandlerError::Start { err, .. } if err.kind() == io::ErrorKind::NotFound => {
+            EXIT_TOOL_MISSING
+        }
+        HandlerError::NoSchemeHandler(_) | HandlerError::RemoteFile(_) => EXIT_TOOL_MISSING,
+        _ => EXIT_ACTION_FAILED,
+    }
+}
+
+/// Open files or URLs in order, and get exit code
+pub(crate) fn open(paths: &[PathBuf], handlers: &HandlerMapping) -> i32 {
+    paths.iter().fold(0, |code, path| {
+        match handlers.handle_path(&RsopMode::XdgOpen, path) {
+            Ok(()) => code,
+            Err(err) => {
+                log::error!("Failed to open {path:?}: {err}");
+                if code == 0 { exit_code(&err) } else { code }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::ExitStatus;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parse_args_paths() {
+        assert_eq!(
+            parse_args(args(&["a.txt", "https://example.com"])),
+            Ok(Args::Open(vec![
+                PathBuf::from("a.txt"),
+                PathBuf::from("https://example.com")
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_args_commands() {
+        assert_eq!(parse_args(args(&["--help"])), Ok(Args::Help));
+        assert_eq!(parse_args(args(&["a.txt", "--manual"])), Ok(Args::Manual));
+        assert_eq!(parse_args(args(&["--version", "-x"])), Ok(Args::Version));
+    }
+
+    #[test]
+    fn parse_args_syntax_errors() {
+        assert_eq!(
+            parse_args(args(&[])),
+            Err("file or URL argument missing".to_owned())
+        );
+        assert_eq!(
+            parse_args(args(&["a.txt", "-x"])),
+            Err("unexpected option \"-x\"".to_owned())
+        );
+        assert!(parse_args(args(&["-"])).is_err());
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(
+            exit_code(&HandlerError::Input {
+                err: io::Error::from(io::ErrorKind::NotFound),
+                path: PathBuf::from("/nonexistent"),
+            }),
+            EXIT_FILE_MISSING
+        );
+        assert_eq!(
+            exit_code(&HandlerError::Input {
+                err: io::Error::from(io::ErrorKind::PermissionDenied),
+                path: PathBuf::from("/root"),
+            }),
+            EXIT_ACTION_FAILED
+        );
+        assert_eq!(
+            exit_code(&HandlerError::Start {
+                err: io::Error::from(io::ErrorKind::NotFound),
+                cmd: vec!["nonexistent".to_owned()],
+            }),
+            EXIT_TOOL_MISSING
+        );
+        assert_eq!(
+            exit_code(&HandlerError::NoSchemeHandler("gopher".to_owned())),
+            EXIT_TOOL_MISSING
+        );
+        assert_eq!(
+            exit_code(&HandlerError::RemoteFile(
+                "file://otherhost/a.txt".to_owned()
+            )),
+            EXIT_TOOL_MISSING
+        );
+        assert_eq!(
+            exit_code(&HandlerError::Exit {
+                status: ExitStatus::default(),
+                cmd: vec!["false".to_owned()],
+                stderr: Vec::new(),
+            }),
+            EXIT_ACTION_FAILED
+        );
+    }
+}